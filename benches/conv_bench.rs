@@ -0,0 +1,107 @@
+//! Compares the im2col convolution against the naive direct convolution.
+//!
+//! Run with `cargo bench --bench conv_bench`.
+
+use conv_nn::activation::Activation;
+use conv_nn::conv_layers::{ConvLayer, Padding};
+use conv_nn::optimizer::OptimizerAlg;
+use ndarray::Array4;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20;
+
+/// Average time of one forward and backward pass through `conv_layer`
+fn time_pass<F>(conv_layer: &mut ConvLayer, input: &Array4<f32>, pass: F) -> Duration
+where
+    F: Fn(&mut ConvLayer, Array4<f32>) -> Array4<f32>,
+{
+    // Warm up so the first iteration's allocations are not measured
+    black_box(pass(conv_layer, input.clone()));
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(pass(conv_layer, input.clone()));
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn bench(
+    name: &str,
+    batch_size: usize,
+    input_size: (usize, usize, usize),
+    kernel_size: (usize, usize),
+    stride: (usize, usize),
+    padding: Padding,
+    num_filters: usize,
+) {
+    let mut conv_layer = ConvLayer::new(
+        input_size,
+        kernel_size,
+        stride,
+        padding,
+        num_filters,
+        Activation::Relu,
+        OptimizerAlg::SGD(0.01),
+    );
+    let input = Array4::<f32>::from_shape_fn(
+        (batch_size, input_size.0, input_size.1, input_size.2),
+        |(n, x, y, c)| ((n * 19 + x * 7 + y * 3 + c * 5) as f32).sin(),
+    );
+
+    let im2col = time_pass(&mut conv_layer, &input, |layer, input| {
+        let output = layer.forward_propagate(input);
+        layer.back_propagate(output)
+    });
+    let naive = time_pass(&mut conv_layer, &input, |layer, input| {
+        let output = layer.forward_propagate_naive(input);
+        layer.back_propagate_naive(output)
+    });
+
+    println!(
+        "{:<28} naive: {:>9.3?}  im2col: {:>9.3?}  speedup: {:.1}x",
+        name,
+        naive,
+        im2col,
+        naive.as_secs_f64() / im2col.as_secs_f64()
+    );
+}
+
+fn main() {
+    bench(
+        "28x28x1 -> 8@3x3",
+        32,
+        (28, 28, 1),
+        (3, 3),
+        (1, 1),
+        Padding::Valid,
+        8,
+    );
+    bench(
+        "28x28x1 -> 32@5x5 same",
+        32,
+        (28, 28, 1),
+        (5, 5),
+        (1, 1),
+        Padding::Same,
+        32,
+    );
+    bench(
+        "14x14x16 -> 32@3x3",
+        32,
+        (14, 14, 16),
+        (3, 3),
+        (1, 1),
+        Padding::Valid,
+        32,
+    );
+    bench(
+        "32x64x3 -> 16@3x3 /2",
+        16,
+        (32, 64, 3),
+        (3, 3),
+        (2, 2),
+        Padding::Same,
+        16,
+    );
+}
@@ -1,19 +1,20 @@
-use ndarray::Array1;
+use ndarray::{Array, Array1, Array2, Axis, Dimension};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum Activation {
     Relu,
     Sigmoid,
     Softmax,
+    Linear,
 }
 
-// How to make this generic?
 pub fn forward(x: Array1<f32>, activation: Activation) -> Array1<f32> {
     match activation {
         Activation::Relu => relu(x),
         Activation::Sigmoid => sigmoid(x),
         Activation::Softmax => softmax(x),
+        Activation::Linear => x,
     }
 }
 
@@ -22,6 +23,52 @@ pub fn backward(x: Array1<f32>, activation: Activation) -> Array1<f32> {
         Activation::Relu => relu_derivative(x),
         Activation::Sigmoid => sigmoid_derivative(x),
         Activation::Softmax => softmax_derivative(x),
+        Activation::Linear => Array1::ones(x.len()),
+    }
+}
+
+/// Applies the activation to a batch, one sample per row
+pub fn forward_batch(x: Array2<f32>, activation: Activation) -> Array2<f32> {
+    match activation {
+        Activation::Relu => relu(x),
+        Activation::Sigmoid => sigmoid(x),
+        // Softmax normalises over each sample, so it has to be applied row by row
+        Activation::Softmax => {
+            let mut x = x;
+            for mut row in x.rows_mut() {
+                let probabilities = softmax(row.to_owned());
+                row.assign(&probabilities);
+            }
+            x
+        }
+        Activation::Linear => x,
+    }
+}
+
+/// Derivative of the activation for a batch, one sample per row
+pub fn backward_batch(x: Array2<f32>, activation: Activation) -> Array2<f32> {
+    match activation {
+        Activation::Relu => relu_derivative(x),
+        Activation::Sigmoid => sigmoid_derivative(x),
+        Activation::Softmax => softmax_derivative(x),
+        Activation::Linear => Array2::ones(x.dim()),
+    }
+}
+
+/// Turns the error on the outputs of a batch into the error on the activation inputs.
+/// Softmax ties every output of a sample together, so it goes through the full Jacobian
+/// rather than just the derivative on its diagonal.
+pub fn backward_batch_error(
+    output: &Array2<f32>,
+    error: Array2<f32>,
+    activation: Activation,
+) -> Array2<f32> {
+    match activation {
+        Activation::Softmax => {
+            let weighted = (&error * output).sum_axis(Axis(1)).insert_axis(Axis(1));
+            (error - &weighted) * output
+        }
+        _ => error * backward_batch(output.clone(), activation),
     }
 }
 
@@ -32,22 +79,23 @@ fn softmax(x: Array1<f32>) -> Array1<f32> {
     exps / sum
 }
 
-fn softmax_derivative(x: Array1<f32>) -> Array1<f32> {
-    Array1::ones(x.len())
+/// Diagonal of the softmax Jacobian, taking the softmax output like `sigmoid_derivative`
+fn softmax_derivative<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
+    x.mapv(|xi| xi * (1.0 - xi))
 }
 
-fn sigmoid(x: Array1<f32>) -> Array1<f32> {
+fn sigmoid<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
     x.mapv(|xi| 1.0 / (1.0 + (-xi).exp()))
 }
 
-fn sigmoid_derivative(x: Array1<f32>) -> Array1<f32> {
+fn sigmoid_derivative<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
     x.mapv(|xi| xi * (1.0 - xi))
 }
 
-fn relu(x: Array1<f32>) -> Array1<f32> {
+fn relu<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
     x.mapv(|xi| if xi > 0.0 { xi } else { 0.0 })
 }
 
-fn relu_derivative(x: Array1<f32>) -> Array1<f32> {
+fn relu_derivative<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
     x.mapv(|xi| if xi > 0.0 { 1.0 } else { 0.0 })
 }
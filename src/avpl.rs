@@ -0,0 +1,181 @@
+use ndarray::{s, Array4, Axis};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use std::ops::AddAssign;
+
+/// Defines an `AveragePoolingLayer` structure.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AvplLayer {
+    input_size: (usize, usize, usize),
+    kernel_size: (usize, usize),
+    pub output_size: (usize, usize, usize),
+    stride: (usize, usize),
+}
+
+impl Debug for AvplLayer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut s = String::new();
+        s.push_str("Average Pooling Layer\n");
+        s.push_str(&format!(
+            "Input Size: {}x{}x{}\n",
+            self.input_size.0, self.input_size.1, self.input_size.2
+        ));
+        s.push_str(&format!(
+            "Kernel Size: {}x{}\n",
+            self.kernel_size.0, self.kernel_size.1
+        ));
+        s.push_str(&format!(
+            "Output Size: {}x{}x{}\n",
+            self.output_size.0, self.output_size.1, self.output_size.2
+        ));
+        s.push_str(&format!("Stride: {}x{}\n", self.stride.0, self.stride.1));
+
+        write!(f, "{}", s)
+    }
+}
+
+impl AvplLayer {
+    /// Create a new average pooling layer with the given parameters
+    pub fn new(
+        input_size: (usize, usize, usize),
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+    ) -> AvplLayer {
+        let output_size = (
+            ((input_size.0 - kernel_size.0) / stride.0) + 1,
+            ((input_size.1 - kernel_size.1) / stride.1) + 1,
+            input_size.2,
+        );
+        let layer: AvplLayer = AvplLayer {
+            input_size,
+            kernel_size,
+            output_size,
+            stride,
+        };
+
+        layer
+    }
+
+    pub fn zero(&mut self) {}
+
+    /// Forward a batch of inputs shaped (batch, x, y, channels)
+    pub fn forward_propagate(&mut self, input: Array4<f32>) -> Array4<f32> {
+        self.infer(input)
+    }
+
+    /// Forward without touching the layer; average pooling keeps no state anyway
+    pub fn infer(&self, input: Array4<f32>) -> Array4<f32> {
+        let batch_size = input.dim().0;
+        let (ox, oy, oc) = self.output_size;
+        let mut output: Array4<f32> = Array4::<f32>::zeros((batch_size, ox, oy, oc));
+        let (kx, ky) = self.kernel_size;
+
+        for y in 0..oy {
+            for x in 0..ox {
+                let (ix, iy) = (x * self.stride.0, y * self.stride.1);
+                let window = input.slice(s![.., ix..ix + kx, iy..iy + ky, ..]);
+                output
+                    .slice_mut(s![.., x, y, ..])
+                    .assign(&window.sum_axis(Axis(1)).sum_axis(Axis(1)));
+            }
+        }
+
+        output / (kx * ky) as f32
+    }
+
+    pub fn back_propagate(&mut self, error: Array4<f32>) -> Array4<f32> {
+        let batch_size = error.dim().0;
+        let (ix_size, iy_size, ic) = self.input_size;
+        let mut prev_error: Array4<f32> = Array4::<f32>::zeros((batch_size, ix_size, iy_size, ic));
+        let (kx, ky) = self.kernel_size;
+        let error = error / (kx * ky) as f32;
+
+        for y in 0..self.output_size.1 {
+            for x in 0..self.output_size.0 {
+                let (ix, iy) = (x * self.stride.0, y * self.stride.1);
+                // Broadcast the (batch, channels) error over the window
+                let position_error = error
+                    .slice(s![.., x, y, ..])
+                    .insert_axis(Axis(1))
+                    .insert_axis(Axis(1));
+                prev_error
+                    .slice_mut(s![.., ix..ix + kx, iy..iy + ky, ..])
+                    .add_assign(&position_error);
+            }
+        }
+
+        prev_error
+    }
+
+    pub fn update(&mut self, _minibatch_size: usize) {}
+}
+
+/// Defines a `GlobalAveragePoolingLayer` structure, averaging each channel down to a single value.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GlobalAvplLayer {
+    input_size: (usize, usize, usize),
+    pub output_size: (usize, usize, usize),
+}
+
+impl Debug for GlobalAvplLayer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut s = String::new();
+        s.push_str("Global Average Pooling Layer\n");
+        s.push_str(&format!(
+            "Input Size: {}x{}x{}\n",
+            self.input_size.0, self.input_size.1, self.input_size.2
+        ));
+        s.push_str(&format!(
+            "Output Size: {}x{}x{}\n",
+            self.output_size.0, self.output_size.1, self.output_size.2
+        ));
+
+        write!(f, "{}", s)
+    }
+}
+
+impl GlobalAvplLayer {
+    /// Create a new global average pooling layer for the given input size
+    pub fn new(input_size: (usize, usize, usize)) -> GlobalAvplLayer {
+        GlobalAvplLayer {
+            input_size,
+            output_size: (1, 1, input_size.2),
+        }
+    }
+
+    pub fn zero(&mut self) {}
+
+    /// Forward a batch of inputs shaped (batch, x, y, channels)
+    pub fn forward_propagate(&mut self, input: Array4<f32>) -> Array4<f32> {
+        self.infer(input)
+    }
+
+    /// Forward without touching the layer; global average pooling keeps no state anyway
+    pub fn infer(&self, input: Array4<f32>) -> Array4<f32> {
+        let batch_size = input.dim().0;
+        let area = (self.input_size.0 * self.input_size.1) as f32;
+        let means = input.sum_axis(Axis(1)).sum_axis(Axis(1)) / area;
+
+        means
+            .into_shape_with_order((batch_size, 1, 1, self.input_size.2))
+            .unwrap()
+    }
+
+    pub fn back_propagate(&mut self, error: Array4<f32>) -> Array4<f32> {
+        let batch_size = error.dim().0;
+        let area = (self.input_size.0 * self.input_size.1) as f32;
+        let error = error / area;
+
+        error
+            .broadcast((
+                batch_size,
+                self.input_size.0,
+                self.input_size.1,
+                self.input_size.2,
+            ))
+            .unwrap()
+            .to_owned()
+    }
+
+    pub fn update(&mut self, _minibatch_size: usize) {}
+}
@@ -0,0 +1,83 @@
+use crate::early_stopping::{EarlyStopper, EarlyStopping};
+use crate::layer::Layer;
+use serde::{Deserialize, Serialize};
+
+/// Sums over the minibatches of an epoch trained so far
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct EpochTotals {
+    /// Accuracy summed over the samples seen
+    pub accuracy: f32,
+    /// Loss summed over the samples seen
+    pub loss: f32,
+    /// Global gradient norm summed over the minibatches
+    pub gradient_norm: f32,
+    pub samples: usize,
+}
+
+/// How far a call to `CNN::train` has got. Checkpoints save it next to the model, so
+/// training a model loaded from a checkpoint carries on from the next minibatch rather
+/// than starting the run over.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TrainingProgress {
+    /// Epochs completed in this run
+    pub epoch: usize,
+    /// Minibatches of the current epoch in their shuffled order, empty until it starts
+    pub batches: Vec<Vec<usize>>,
+    /// Minibatches of the current epoch already trained on
+    pub batch: usize,
+    pub totals: EpochTotals,
+    /// Rollbacks after divergence used up in this run
+    pub rollbacks: usize,
+    /// Best accuracies that the saving strategies compare against
+    pub best_train_acc: f32,
+    pub best_test_acc: f32,
+    pub best_val_acc: f32,
+    pub stopper: Option<EarlyStopper>,
+    /// Layers from the best epoch, kept when early stopping restores them
+    pub best_layers: Option<Vec<Layer>>,
+}
+
+impl TrainingProgress {
+    pub fn new(early_stopping: Option<EarlyStopping>) -> TrainingProgress {
+        TrainingProgress {
+            stopper: early_stopping.map(EarlyStopper::new),
+            ..TrainingProgress::default()
+        }
+    }
+
+    /// Whether the run has trained `epochs` epochs or been stopped early
+    pub fn finished(&self, epochs: usize) -> bool {
+        self.epoch >= epochs || self.stopper.as_ref().is_some_and(EarlyStopper::should_stop)
+    }
+
+    /// Adds the results of one minibatch of `samples` samples
+    pub fn record_batch(&mut self, accuracy: f32, loss: f32, samples: usize, gradient_norm: f32) {
+        self.totals.accuracy += accuracy * samples as f32;
+        self.totals.loss += loss * samples as f32;
+        self.totals.gradient_norm += gradient_norm;
+        self.totals.samples += samples;
+        self.batch += 1;
+    }
+
+    /// Mean accuracy, loss and gradient norm of the minibatches trained this epoch
+    pub fn epoch_means(&self) -> (f32, f32, f32) {
+        let samples = self.totals.samples.max(1) as f32;
+        (
+            self.totals.accuracy / samples,
+            self.totals.loss / samples,
+            self.totals.gradient_norm / self.batch.max(1) as f32,
+        )
+    }
+
+    /// Forgets the current epoch so it runs again on a fresh shuffle
+    pub fn restart_epoch(&mut self) {
+        self.batches.clear();
+        self.batch = 0;
+        self.totals = EpochTotals::default();
+    }
+
+    pub fn end_epoch(&mut self) {
+        self.restart_epoch();
+        self.epoch += 1;
+    }
+}
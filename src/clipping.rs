@@ -0,0 +1,40 @@
+use ndarray::{Array, Dimension};
+use serde::{Deserialize, Serialize};
+
+/// Limits the minibatch gradients before the optimizer step, guarding against the
+/// exploding gradients of deep stacks.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum GradientClipping {
+    /// Leave the gradients as they are
+    #[default]
+    None,
+    /// Clamp every averaged gradient component to `[-value, value]`
+    Value(f32),
+    /// Scale all gradients of the network down together so their global L2 norm is at most
+    /// the given value
+    Norm(f32),
+}
+
+impl GradientClipping {
+    /// Clips gradients summed over `minibatch_size` samples, given the global norm of the
+    /// averaged gradients of the whole network
+    pub fn apply<D: Dimension>(
+        &self,
+        changes: &mut Array<f32, D>,
+        global_norm: f32,
+        minibatch_size: usize,
+    ) {
+        match *self {
+            GradientClipping::None => {}
+            GradientClipping::Value(value) => {
+                let limit = value * minibatch_size as f32;
+                changes.mapv_inplace(|x| x.clamp(-limit, limit));
+            }
+            GradientClipping::Norm(max_norm) => {
+                if global_norm > max_norm {
+                    *changes *= max_norm / global_norm;
+                }
+            }
+        }
+    }
+}
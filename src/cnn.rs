@@ -1,19 +1,33 @@
 use crate::activation::Activation;
-use crate::conv_layers::ConvLayer;
+use crate::avpl::{AvplLayer, GlobalAvplLayer};
+use crate::checkpoint::TrainingProgress;
+use crate::clipping::GradientClipping;
+use crate::conv_layers::{ConvLayer, Padding};
 use crate::dense_layer::DenseLayer;
+use crate::divergence::{DivergenceAction, DivergenceGuard};
+use crate::early_stopping::{EarlyStopping, EpochMetrics};
+use crate::error::{Error, Result};
 use crate::layer::Layer;
+use crate::loss::Loss;
 use crate::mnist_impl::*;
 use crate::mxpl::MxplLayer;
 use crate::optimizer::OptimizerAlg;
+use crate::output::{write_atomically, Checkpoint, OutputConfig, Retention};
+use crate::regularization::Regularization;
+use crate::scheduler::{LrSchedule, LrScheduler};
 use crate::utils::*;
-use core::panic;
 use indicatif::{ProgressBar, ProgressStyle};
-use ndarray::{Array1, Array3};
+use ndarray::{Array2, Array4, ArrayView4, Axis};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use serde::{Deserialize, Serialize};
 use std::default::Default;
 use std::fmt::{Debug, Formatter};
 use std::fs::File;
-use std::io::Write;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct Hyperparameters {
@@ -23,6 +37,33 @@ pub struct Hyperparameters {
     pub saving_strategy: SavingStrategy,
     pub name: String,
     pub verbose: bool,
+    /// Worker threads used to split each minibatch during training, 1 trains sequentially
+    pub threads: usize,
+    /// Seeds weight initialisation, dropout and batch sampling. Runs with the same seed
+    /// (and thread count) are bit-identical, None seeds from the OS
+    pub seed: Option<u64>,
+    /// Loss minimised during training
+    pub loss: Loss,
+    /// How the optimizer's learning rate changes over training
+    pub lr_schedule: LrSchedule,
+    /// Weight penalties given to every layer added afterwards, see `CNN::set_regularization`
+    /// to change a single layer
+    pub regularization: Regularization,
+    /// Clipping of the gradients before every optimizer step
+    pub gradient_clipping: GradientClipping,
+    /// What to do when training produces NaN or infinite values
+    pub divergence_guard: DivergenceGuard,
+    /// Ends training early once the monitored metric stops improving, None always runs
+    /// every epoch
+    pub early_stopping: Option<EarlyStopping>,
+    /// Holds part of the training set out for validation whenever the data is supplied,
+    /// None keeps the data's own validation set, which may be empty
+    pub validation_split: Option<ValidationSplit>,
+    /// Where `save` and the checkpoints write their files
+    pub output: OutputConfig,
+    /// Writes a checkpoint after every epoch, which `CNN::resume` continues training from,
+    /// keeping the files chosen by the policy. None writes no checkpoints.
+    pub checkpoint_retention: Option<Retention>,
 }
 
 impl Default for Hyperparameters {
@@ -34,6 +75,17 @@ impl Default for Hyperparameters {
             saving_strategy: SavingStrategy::Never,
             name: String::from("model"),
             verbose: true,
+            threads: 1,
+            seed: None,
+            loss: Loss::CrossEntropy,
+            lr_schedule: LrSchedule::Constant,
+            regularization: Regularization::none(),
+            gradient_clipping: GradientClipping::None,
+            divergence_guard: DivergenceGuard::default(),
+            early_stopping: None,
+            validation_split: None,
+            output: OutputConfig::default(),
+            checkpoint_retention: None,
         }
     }
 }
@@ -42,41 +94,144 @@ impl Default for Hyperparameters {
 pub struct CNN {
     pub layers: Vec<Layer>,
     pub layer_order: Vec<String>,
+    /// Not saved with the model, see `set_data` to supply it after loading
+    #[serde(skip)]
     pub data: TrainingData,
     pub minibatch_size: usize,
     pub creation_time: SystemTime,
     pub saving_strategy: SavingStrategy,
     pub training_history: Vec<f32>,
     pub testing_history: Vec<f32>,
+    #[serde(default)]
+    pub validation_history: Vec<f32>,
     pub time_history: Vec<usize>,
+    #[serde(default)]
+    pub training_loss_history: Vec<f32>,
+    #[serde(default)]
+    pub testing_loss_history: Vec<f32>,
+    #[serde(default)]
+    pub validation_loss_history: Vec<f32>,
+    /// Mean global gradient norm of every epoch, measured before clipping
+    #[serde(default)]
+    pub gradient_norm_history: Vec<f32>,
     pub name: String,
     pub verbose: bool,
     pub optimizer: OptimizerAlg,
     pub epochs: usize,
     pub input_shape: (usize, usize, usize),
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+    #[serde(skip)]
+    thread_pool: Option<ThreadPool>,
+    /// Layers each worker runs its part of a minibatch on, kept between minibatches
+    #[serde(skip)]
+    replicas: Vec<Vec<Layer>>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub loss: Loss,
+    #[serde(default)]
+    pub scheduler: LrScheduler,
+    #[serde(default)]
+    pub regularization: Regularization,
+    #[serde(default)]
+    pub gradient_clipping: GradientClipping,
+    #[serde(default)]
+    pub divergence_guard: DivergenceGuard,
+    #[serde(default)]
+    pub early_stopping: Option<EarlyStopping>,
+    /// Validation set taken out of the training data by `set_data`
+    #[serde(default)]
+    pub validation_split: Option<ValidationSplit>,
+    /// Seeds the validation split, so the same samples are held out every time the data
+    /// is supplied
+    #[serde(default)]
+    split_seed: u64,
+    /// First layer whose outputs went non-finite in the last minibatch, when checked
+    #[serde(skip)]
+    non_finite_output: Option<usize>,
+    /// Where the current call to `train` has got to, None between runs. Only checkpoints
+    /// save it, models saved by `save` start a new run when trained again.
+    #[serde(skip)]
+    pub progress: Option<TrainingProgress>,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub checkpoint_retention: Option<Retention>,
+    /// Checkpoints of this model still on disk, oldest first
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+    /// Saved so a resumed run shuffles and seeds exactly as an uninterrupted one would
+    #[serde(default = "ChaCha8Rng::from_entropy")]
+    rng: ChaCha8Rng,
 }
 
+/// A checkpoint file as written: the model as `save` writes it, and the progress of its run
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    model: &'a CNN,
+    progress: &'a Option<TrainingProgress>,
+}
+
+/// A checkpoint file as read back
+#[derive(Deserialize)]
+struct CheckpointFile {
+    model: CNN,
+    progress: Option<TrainingProgress>,
+}
+
+fn default_threads() -> usize {
+    1
+}
+
+/// Fetches the samples at the given indices from one part of the dataset
+type BatchFetcher = fn(&TrainingData, &[usize]) -> Result<(Array4<f32>, Vec<usize>)>;
+
 impl Debug for CNN {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut s = String::new();
-        let time = self
-            .creation_time
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-        s.push_str(&format!("File: models/{}_{}.json\n", self.name, time));
+        let time = self.creation_millis();
+        s.push_str(&format!("File: {}\n", self.model_path("json").display()));
         s.push_str(&format!("Time: {}\n", time));
         s.push_str(&format!("Minibatch size: {}\n", self.minibatch_size));
         s.push_str(&format!("Training size: {}\n", self.data.trn_size));
+        s.push_str(&format!("Validation size: {}\n", self.data.val_size));
         s.push_str(&format!("Testing size: {}\n", self.data.tst_size));
-        s.push_str(&format!("\nLayers:\n"));
+        s.push_str(&format!("Loss: {:?}\n", self.loss));
+        s.push_str(&format!(
+            "Learning rate schedule: {:?}\n",
+            self.scheduler.schedule
+        ));
+        s.push_str(&format!(
+            "Gradient clipping: {:?}\n",
+            self.gradient_clipping
+        ));
+        s.push_str(&format!("Early stopping: {:?}\n", self.early_stopping));
+        s.push_str("\nLayers:\n");
 
         for layer in &self.layers {
             s.push_str(&format!("{:?}\n", layer));
         }
 
         s.push_str(&format!("Training accuracy: {:?}\n", self.training_history));
+        s.push_str(&format!(
+            "Validation accuracy: {:?}\n",
+            self.validation_history
+        ));
         s.push_str(&format!("Testing accuracy: {:?}\n", self.testing_history));
+        s.push_str(&format!(
+            "Training loss: {:?}\n",
+            self.training_loss_history
+        ));
+        s.push_str(&format!(
+            "Validation loss: {:?}\n",
+            self.validation_loss_history
+        ));
+        s.push_str(&format!("Testing loss: {:?}\n", self.testing_loss_history));
+        s.push_str(&format!(
+            "Gradient norm: {:?}\n",
+            self.gradient_norm_history
+        ));
         s.push_str(&format!("Time taken: {:?}\n", self.time_history));
 
         write!(f, "{}", s)
@@ -86,84 +241,229 @@ impl Debug for CNN {
 impl CNN {
     pub fn new(data: TrainingData, params: Hyperparameters) -> CNN {
         let creation_time = std::time::SystemTime::now();
+        let mut rng = match params.seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+            None => ChaCha8Rng::from_entropy(),
+        };
+        let split_seed = rng.gen();
 
-        let cnn: CNN = CNN {
+        let mut cnn: CNN = CNN {
             layers: vec![],
             layer_order: vec![],
-            data,
+            data: TrainingData::default(),
             minibatch_size: params.batch_size,
             creation_time,
             saving_strategy: params.saving_strategy,
             training_history: vec![],
             testing_history: vec![],
+            validation_history: vec![],
             time_history: vec![],
+            training_loss_history: vec![],
+            testing_loss_history: vec![],
+            validation_loss_history: vec![],
+            gradient_norm_history: vec![],
             name: params.name,
             verbose: params.verbose,
             optimizer: params.optimizer,
             epochs: params.epochs,
             input_shape: (0, 0, 0),
+            threads: params.threads.max(1),
+            thread_pool: None,
+            replicas: Vec::new(),
+            seed: params.seed,
+            loss: params.loss,
+            scheduler: LrScheduler::new(params.lr_schedule),
+            regularization: params.regularization,
+            gradient_clipping: params.gradient_clipping,
+            divergence_guard: params.divergence_guard,
+            early_stopping: params.early_stopping,
+            validation_split: params.validation_split,
+            split_seed,
+            non_finite_output: None,
+            progress: None,
+            output: params.output,
+            checkpoint_retention: params.checkpoint_retention,
+            checkpoints: vec![],
+            rng,
         };
+        cnn.set_data(data);
 
         cnn
     }
 
-    pub fn load(model_file_name: &str) -> CNN {
-        let model_file = File::open(model_file_name).unwrap();
-        let cnn: CNN = serde_json::from_reader(model_file).unwrap();
+    /// Supplies the dataset to train and test on, which models do not save. The validation
+    /// split given at creation, if any, is taken out of it again, holding out the same
+    /// samples as before for the same data.
+    pub fn set_data(&mut self, mut data: TrainingData) {
+        if let Some(split) = self.validation_split {
+            let mut rng = ChaCha8Rng::seed_from_u64(self.split_seed);
+            split_validation(&mut data, split, &mut rng);
+        }
+        self.data = data;
+    }
 
-        cnn
+    /// Loads a model saved as JSON, ready to train or predict
+    pub fn load(model_file_name: &str) -> Result<CNN> {
+        let model_file = File::open(model_file_name)?;
+        let mut cnn: CNN = serde_json::from_reader(model_file)?;
+        // The gradient buffers and caches are not saved
+        cnn.zero();
+
+        Ok(cnn)
     }
 
-    pub fn load_binary(model_file_name: &str) -> CNN {
-        let model_file = File::open(model_file_name).unwrap();
-        let cnn: CNN = bincode::deserialize_from(model_file).unwrap();
+    pub fn load_binary(model_file_name: &str) -> Result<CNN> {
+        let model_file = File::open(model_file_name)?;
+        let mut cnn: CNN = bincode::deserialize_from(model_file)?;
+        cnn.zero();
 
-        cnn
+        Ok(cnn)
+    }
+
+    /// Loads a JSON model saved along with its training data, as every model used to be,
+    /// keeping the data so training can carry on. `load` reads these files too but drops
+    /// the data, and saving the model again writes it without. The old binary files have
+    /// no such migration, load the JSON file saved next to them instead.
+    pub fn load_legacy(model_file_name: &str) -> Result<CNN> {
+        let model_file = File::open(model_file_name)?;
+        let mut model: serde_json::Value = serde_json::from_reader(model_file)?;
+        let data = match model.as_object_mut().and_then(|model| model.remove("data")) {
+            Some(data) => serde_json::from_value(data)?,
+            None => TrainingData::default(),
+        };
+        let mut cnn: CNN = serde_json::from_value(model)?;
+        cnn.zero();
+        // Saved after the validation split, so it is used as it is
+        cnn.data = data;
+
+        Ok(cnn)
     }
 
-    pub fn set_input_shape(&mut self, input_shape: Vec<usize>) {
+    /// Loads a checkpoint written by `save_checkpoint` along with the progress of its run,
+    /// which `train` carries on once the data is supplied
+    pub fn load_checkpoint(checkpoint_file_name: &str) -> Result<CNN> {
+        let checkpoint_file = File::open(checkpoint_file_name)?;
+        let checkpoint: CheckpointFile = serde_json::from_reader(checkpoint_file)?;
+        let mut cnn = checkpoint.model;
+        cnn.progress = checkpoint.progress;
+        cnn.zero();
+
+        Ok(cnn)
+    }
+
+    /// Loads a checkpoint written during training and finishes its run on `data`,
+    /// continuing from the minibatch after the one it was saved at with the same RNG,
+    /// optimizer and schedule state. A model saved outside of a run is returned as it is.
+    pub fn resume(checkpoint_file_name: &str, data: TrainingData) -> Result<CNN> {
+        let mut cnn = CNN::load_checkpoint(checkpoint_file_name)?;
+        cnn.set_data(data);
+        if cnn.progress.is_some() {
+            cnn.train()?;
+        }
+
+        Ok(cnn)
+    }
+
+    /// Sets the (x, y, channels) shape of the input images, missing trailing dimensions default to 1
+    pub fn set_input_shape(&mut self, input_shape: Vec<usize>) -> Result<()> {
+        if input_shape.is_empty() || input_shape.len() > 3 || input_shape.contains(&0) {
+            return Err(Error::InvalidInputShape(input_shape));
+        }
         let mut iter = input_shape.into_iter();
         self.input_shape = (
-            iter.next().unwrap(),
             iter.next().unwrap_or(1),
             iter.next().unwrap_or(1),
-        )
+            iter.next().unwrap_or(1),
+        );
+
+        Ok(())
     }
 
-    pub fn add_conv_layer(&mut self, num_filters: usize, kernel_size: usize) {
-        if self.input_shape.0 == 0 {
-            panic!("Input shape not set, use cnn.set_input_shape()");
+    pub fn add_conv_layer(
+        &mut self,
+        num_filters: usize,
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+        padding: Padding,
+        activation: Activation,
+    ) -> Result<()> {
+        let input_size = self.spatial_input_size("Convolutional Layer")?;
+        if num_filters == 0 {
+            return Err(Error::InvalidLayer(String::from(
+                "Convolutional Layer needs at least one filter",
+            )));
         }
-        let input_size: (usize, usize, usize) = match self.layers.last() {
-            Some(Layer::Conv(conv_layer)) => conv_layer.output_size,
-            Some(Layer::Mxpl(mxpl_layer)) => mxpl_layer.output_size,
-            Some(Layer::Dense(_)) => panic!("Convolutional Layer cannot follow a Dense Layer"),
-            None => self.input_shape,
-        };
-        let conv_layer: ConvLayer = ConvLayer::new(
+        check_window(
+            "Convolutional Layer",
             input_size,
             kernel_size,
-            1,
+            stride,
+            padding,
+        )?;
+        let mut conv_layer: ConvLayer = ConvLayer::new_with_rng(
+            input_size,
+            kernel_size,
+            stride,
+            padding,
             num_filters,
-            self.optimizer.clone(),
+            activation,
+            self.optimizer,
+            &mut self.rng,
         );
+        conv_layer.regularization = self.regularization;
         self.layers.push(Layer::Conv(conv_layer));
         self.layer_order.push(String::from("conv"));
+
+        Ok(())
     }
 
-    pub fn add_mxpl_layer(&mut self, kernel_size: usize) {
-        if self.input_shape.0 == 0 {
-            panic!("Input shape not set, use cnn.set_input_shape()");
-        }
-        let input_size: (usize, usize, usize) = match self.layers.last() {
-            Some(Layer::Conv(conv_layer)) => conv_layer.output_size,
-            Some(Layer::Mxpl(mxpl_layer)) => mxpl_layer.output_size,
-            Some(Layer::Dense(_)) => panic!("Max Pooling Layer cannot follow a Dense Layer"),
-            None => self.input_shape,
-        };
-        let mxpl_layer: MxplLayer = MxplLayer::new(input_size, kernel_size, 2);
+    pub fn add_mxpl_layer(
+        &mut self,
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+    ) -> Result<()> {
+        let input_size = self.spatial_input_size("Max Pooling Layer")?;
+        check_window(
+            "Max Pooling Layer",
+            input_size,
+            kernel_size,
+            stride,
+            Padding::Valid,
+        )?;
+        let mxpl_layer: MxplLayer = MxplLayer::new(input_size, kernel_size, stride);
         self.layers.push(Layer::Mxpl(mxpl_layer));
         self.layer_order.push(String::from("mxpl"));
+
+        Ok(())
+    }
+
+    pub fn add_avpl_layer(
+        &mut self,
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+    ) -> Result<()> {
+        let input_size = self.spatial_input_size("Average Pooling Layer")?;
+        check_window(
+            "Average Pooling Layer",
+            input_size,
+            kernel_size,
+            stride,
+            Padding::Valid,
+        )?;
+        let avpl_layer: AvplLayer = AvplLayer::new(input_size, kernel_size, stride);
+        self.layers.push(Layer::Avpl(avpl_layer));
+        self.layer_order.push(String::from("avpl"));
+
+        Ok(())
+    }
+
+    pub fn add_global_avpl_layer(&mut self) -> Result<()> {
+        let input_size = self.spatial_input_size("Global Average Pooling Layer")?;
+        let global_avpl_layer: GlobalAvplLayer = GlobalAvplLayer::new(input_size);
+        self.layers.push(Layer::GlobalAvpl(global_avpl_layer));
+        self.layer_order.push(String::from("global_avpl"));
+
+        Ok(())
     }
 
     pub fn add_dense_layer(
@@ -171,269 +471,1017 @@ impl CNN {
         output_size: usize,
         activation: Activation,
         dropout: Option<f32>,
-    ) {
+    ) -> Result<()> {
         if self.input_shape.0 == 0 {
-            panic!("Input shape not set, use cnn.set_input_shape()");
+            return Err(Error::InputShapeNotSet);
         }
-        // Find last layer's output size
-        let transition_shape: (usize, usize, usize) = match self.layers.last() {
-            Some(Layer::Conv(conv_layer)) => conv_layer.output_size,
-            Some(Layer::Mxpl(mxpl_layer)) => mxpl_layer.output_size,
-            Some(Layer::Dense(dense_layer)) => (dense_layer.output_size, 1, 1),
-            None => self.input_shape,
-        };
+        if output_size == 0 {
+            return Err(Error::InvalidLayer(String::from(
+                "Dense Layer needs at least one output",
+            )));
+        }
+        if let Some(dropout) = dropout {
+            if !(0.0..1.0).contains(&dropout) {
+                return Err(Error::InvalidLayer(format!(
+                    "Dense Layer dropout must be in [0, 1), got {}",
+                    dropout
+                )));
+            }
+        }
+        let transition_shape = self.last_output_size();
         let input_size = transition_shape.0 * transition_shape.1 * transition_shape.2;
-        let fcl_layer: DenseLayer = DenseLayer::new(
+        let mut fcl_layer: DenseLayer = DenseLayer::new_with_rng(
             input_size,
             output_size,
             activation,
             self.optimizer,
             dropout,
             transition_shape,
+            &mut self.rng,
         );
+        fcl_layer.regularization = self.regularization;
         self.layers.push(Layer::Dense(fcl_layer));
         self.layer_order.push(String::from("dense"));
+
+        Ok(())
     }
 
-    pub fn forward_propagate(&mut self, image: Array3<f32>, training: bool) -> Array1<f32> {
-        let mut output: Array3<f32> = image;
-        let mut flat_output: Array1<f32> =
-            output.clone().into_shape_with_order(output.len()).unwrap();
-        for layer in &mut self.layers {
-            match layer {
-                Layer::Conv(conv_layer) => {
-                    output = conv_layer.forward_propagate(output);
-                    flat_output = output.clone().into_shape_with_order(output.len()).unwrap();
+    /// Output size of the last layer, or the input shape when there are no layers yet
+    fn last_output_size(&self) -> (usize, usize, usize) {
+        match self.layers.last() {
+            Some(Layer::Conv(conv_layer)) => conv_layer.output_size,
+            Some(Layer::Mxpl(mxpl_layer)) => mxpl_layer.output_size,
+            Some(Layer::Avpl(avpl_layer)) => avpl_layer.output_size,
+            Some(Layer::GlobalAvpl(global_avpl_layer)) => global_avpl_layer.output_size,
+            Some(Layer::Dense(dense_layer)) => (dense_layer.output_size, 1, 1),
+            None => self.input_shape,
+        }
+    }
+
+    /// Input size of a new `layer_name`, which works on images and so cannot follow a
+    /// Dense Layer
+    fn spatial_input_size(&self, layer_name: &str) -> Result<(usize, usize, usize)> {
+        if self.input_shape.0 == 0 {
+            return Err(Error::InputShapeNotSet);
+        }
+        if let Some(Layer::Dense(_)) = self.layers.last() {
+            return Err(Error::InvalidLayer(format!(
+                "{} cannot follow a Dense Layer",
+                layer_name
+            )));
+        }
+
+        Ok(self.last_output_size())
+    }
+
+    /// Overrides the regularization of the layer at `layer_index`, which must have weights
+    pub fn set_regularization(
+        &mut self,
+        layer_index: usize,
+        regularization: Regularization,
+    ) -> Result<()> {
+        match self.layers.get_mut(layer_index) {
+            Some(Layer::Conv(conv_layer)) => conv_layer.regularization = regularization,
+            Some(Layer::Dense(dense_layer)) => dense_layer.regularization = regularization,
+            Some(_) => {
+                return Err(Error::InvalidLayer(format!(
+                    "Layer {} has no weights to regularize",
+                    layer_index
+                )))
+            }
+            None => {
+                return Err(Error::InvalidLayer(format!(
+                    "There is no layer {}",
+                    layer_index
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forward a batch of images shaped (batch, x, y, channels), returning one output row per image
+    pub fn forward_propagate(&mut self, images: Array4<f32>, training: bool) -> Array2<f32> {
+        forward_layers(&mut self.layers, images, training)
+    }
+
+    /// Inference on a batch of images without touching any layer state, so a trained
+    /// model can be shared between threads, e.g. behind an `Arc`
+    pub fn predict(&self, images: Array4<f32>) -> Array2<f32> {
+        infer_layers(&self.layers, images)
+    }
+
+    /// Gradient of the loss on the last layer's logits for the last forwarded batch
+    pub fn last_layer_error(&mut self, labels: &[usize]) -> Result<Array2<f32>> {
+        output_error(&self.layers, labels, self.loss)
+    }
+
+    pub fn back_propagate(&mut self, labels: &[usize], training: bool) -> Result<()> {
+        let error = self.last_layer_error(labels)?;
+        back_propagate_layers(&mut self.layers, error, training);
+
+        Ok(())
+    }
+
+    /// Forwards and back-propagates one minibatch, accumulating its gradients without updating
+    /// the weights, and returns the minibatch accuracy and mean loss.
+    ///
+    /// With more than one thread the minibatch is split into contiguous chunks, each run on a
+    /// replica of the layers, and the replica gradients are summed back in chunk order so the
+    /// result only depends on the thread count.
+    pub fn train_batch(&mut self, images: Array4<f32>, labels: &[usize]) -> Result<(f32, f32)> {
+        last_dense_layer(&self.layers)?;
+        if self.threads <= 1 || labels.len() < 2 {
+            self.forward_propagate(images, true);
+            self.back_propagate(labels, true)?;
+            if self.divergence_guard.check_activations {
+                self.non_finite_output = non_finite_output(&self.layers);
+            }
+            return Ok((self.get_accuracy(labels)?, self.get_loss(labels)?));
+        }
+
+        let pool = self.take_thread_pool();
+
+        let chunk_size = labels.len().div_ceil(self.threads);
+        let chunks: Vec<(ArrayView4<f32>, &[usize])> = images
+            .axis_chunks_iter(Axis(0), chunk_size)
+            .zip(labels.chunks(chunk_size))
+            .collect();
+        if self.replicas.len() < chunks.len() {
+            self.replicas.resize_with(chunks.len(), Vec::new);
+        }
+        let replicas = &mut self.replicas[..chunks.len()];
+        // Every replica gets its own dropout stream, drawn in chunk order
+        for replica in replicas.iter_mut() {
+            // Replicas only take on the current parameters, the optimizer state stays with
+            // the layers
+            replica.truncate(self.layers.len());
+            for (i, layer) in self.layers.iter().enumerate() {
+                match replica.get_mut(i) {
+                    Some(worker_layer) => worker_layer.load_parameters(layer),
+                    None => replica.push(layer.replica()),
                 }
-                Layer::Mxpl(mxpl_layer) => {
-                    output = mxpl_layer.forward_propagate(output);
-                    flat_output = output.clone().into_shape_with_order(output.len()).unwrap();
+            }
+            // Only the gradients of this minibatch are summed back, not any the layers
+            // already hold
+            zero_layers(replica);
+            for layer in replica {
+                if let Layer::Dense(dense_layer) = layer {
+                    dense_layer.seed_dropout(self.rng.gen());
                 }
-                Layer::Dense(dense_layer) => {
-                    flat_output = dense_layer.forward_propagate(flat_output, training);
+            }
+        }
+
+        let loss = self.loss;
+        let totals: Result<Vec<(f32, f32)>> = pool.install(|| {
+            replicas
+                .par_iter_mut()
+                .zip(chunks.par_iter())
+                .map(|(layers, (images, labels))| {
+                    let output = forward_layers(layers, images.to_owned(), true);
+                    let error = output_error(layers, labels, loss)?;
+                    back_propagate_layers(layers, error, true);
+                    let size = labels.len() as f32;
+                    Ok((
+                        accuracy(&output, labels) * size,
+                        loss.loss(&output, labels) * size,
+                    ))
+                })
+                .collect()
+        });
+        self.thread_pool = Some(pool);
+        let totals = totals?;
+
+        if self.divergence_guard.check_activations {
+            self.non_finite_output = replicas
+                .iter()
+                .find_map(|replica| non_finite_output(replica));
+        }
+        for replica in replicas.iter() {
+            for (layer, worker_layer) in self.layers.iter_mut().zip(replica) {
+                match (layer, worker_layer) {
+                    (Layer::Conv(conv_layer), Layer::Conv(worker)) => {
+                        conv_layer.accumulate_gradients(worker)
+                    }
+                    (Layer::Dense(dense_layer), Layer::Dense(worker)) => {
+                        dense_layer.accumulate_gradients(worker)
+                    }
+                    _ => {}
                 }
             }
         }
 
-        flat_output
+        let (accuracy, loss) = mean_metrics(&totals, labels.len());
+        Ok((accuracy, loss + self.regularization_loss()))
     }
 
-    pub fn last_layer_error(&mut self, label: usize) -> Array1<f32> {
-        let size: usize = match self.layers.last().unwrap() {
-            Layer::Dense(dense_layer) => dense_layer.output_size,
-            _ => panic!("Last layer is not a DenseLayer"),
-        };
-        let desired = Array1::<f32>::from_shape_fn(size, |i| (label == i) as usize as f32);
-        self.output() - desired
-    }
+    /// Clips the accumulated gradients and applies one optimizer step to every layer.
+    /// Returns the global L2 norm of the averaged gradients before clipping.
+    pub fn update(&mut self, minibatch_size: usize) -> f32 {
+        let sum_squares: f32 = self
+            .layers
+            .iter()
+            .map(|layer| match layer {
+                Layer::Conv(conv_layer) => conv_layer.gradient_sum_squares(),
+                Layer::Dense(dense_layer) => dense_layer.gradient_sum_squares(),
+                _ => 0.0,
+            })
+            .sum();
+        let norm = sum_squares.sqrt() / minibatch_size as f32;
 
-    pub fn back_propagate(&mut self, label: usize, training: bool) {
-        let mut flat_error: Array1<f32> = self.last_layer_error(label);
-        let mut error: Array3<f32> = flat_error
-            .clone()
-            .into_shape_with_order((1, 1, flat_error.len()))
-            .unwrap();
-        for layer in self.layers.iter_mut().rev() {
+        let clipping = self.gradient_clipping;
+        for layer in &mut self.layers {
             match layer {
                 Layer::Conv(conv_layer) => {
-                    error = conv_layer.back_propagate(error);
-                    // flat_error = error.clone().into_shape(error.len()).unwrap();
-                }
-                Layer::Mxpl(mxpl_layer) => {
-                    error = mxpl_layer.back_propagate(error);
-                    // flat_error = error.clone().into_shape(error.len()).unwrap();
+                    conv_layer.clip_gradients(clipping, norm, minibatch_size);
+                    conv_layer.update(minibatch_size)
                 }
+                Layer::Mxpl(_) => {}
+                Layer::Avpl(_) => {}
+                Layer::GlobalAvpl(_) => {}
                 Layer::Dense(dense_layer) => {
-                    flat_error = dense_layer.back_propagate(flat_error, training);
-                    error = flat_error
-                        .clone()
-                        .into_shape_with_order(dense_layer.transition_shape)
-                        .unwrap();
+                    dense_layer.clip_gradients(clipping, norm, minibatch_size);
+                    dense_layer.update(minibatch_size)
                 }
             }
         }
+
+        norm
     }
 
-    pub fn update(&mut self, minibatch_size: usize) {
-        for layer in &mut self.layers {
-            match layer {
-                Layer::Conv(conv_layer) => conv_layer.update(minibatch_size),
-                Layer::Mxpl(_) => {}
-                Layer::Dense(dense_layer) => dense_layer.update(minibatch_size),
+    /// Returns `Error::NonFinite` for the first layer whose weights or biases hold NaN or
+    /// infinite values
+    pub fn check_finite(&self) -> Result<()> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            let tensor = match layer {
+                Layer::Conv(conv_layer) => conv_layer.non_finite_parameters(),
+                Layer::Dense(dense_layer) => dense_layer.non_finite_parameters(),
+                _ => None,
+            };
+            if let Some(tensor) = tensor {
+                return Err(self.non_finite(i, tensor));
             }
         }
+
+        Ok(())
     }
 
-    pub fn output(&self) -> Array1<f32> {
-        // self.dense_layers.last().unwrap().output.clone()
-        match self.layers.last().unwrap() {
-            Layer::Conv(_) => panic!("Last layer is a ConvLayer"),
-            Layer::Mxpl(_) => panic!("Last layer is a MxplLayer"),
-            Layer::Dense(dense_layer) => dense_layer.output.clone(),
+    /// The error reporting NaN or infinite values in `tensor` of the layer at `layer`
+    fn non_finite(&self, layer: usize, tensor: &'static str) -> Error {
+        Error::NonFinite {
+            layer,
+            kind: self.layer_order.get(layer).cloned().unwrap_or_default(),
+            tensor,
         }
     }
 
-    pub fn get_accuracy(&self, label: usize) -> f32 {
-        let mut max = 0.0;
-        let mut max_idx = 0;
-        let output = self.output();
-        for j in 0..output.len() {
-            if output[j] > max {
-                max = output[j];
-                max_idx = j;
+    /// Sets the learning rate of every layer's optimizer, `self.optimizer` keeps the base rate
+    pub fn set_learning_rate(&mut self, lr: f32) {
+        for layer in &mut self.layers {
+            match layer {
+                Layer::Conv(conv_layer) => conv_layer.set_learning_rate(lr),
+                Layer::Mxpl(_) => {}
+                Layer::Avpl(_) => {}
+                Layer::GlobalAvpl(_) => {}
+                Layer::Dense(dense_layer) => dense_layer.set_learning_rate(lr),
             }
         }
+    }
+
+    pub fn output(&self) -> Result<Array2<f32>> {
+        Ok(last_dense_layer(&self.layers)?.output.clone())
+    }
+
+    /// Mean loss of the last forwarded batch, including the weight penalties
+    pub fn get_loss(&self, labels: &[usize]) -> Result<f32> {
+        Ok(self.loss.loss(&self.output()?, labels) + self.regularization_loss())
+    }
+
+    /// Loss added by the L1 and L2 penalties of every layer
+    pub fn regularization_loss(&self) -> f32 {
+        self.layers
+            .iter()
+            .map(|layer| match layer {
+                Layer::Conv(conv_layer) => conv_layer.regularization_loss(),
+                Layer::Dense(dense_layer) => dense_layer.regularization_loss(),
+                _ => 0.0,
+            })
+            .sum()
+    }
 
-        (max_idx == label) as usize as f32
+    /// Fraction of the last forwarded batch whose highest output matches its label
+    pub fn get_accuracy(&self, labels: &[usize]) -> Result<f32> {
+        Ok(accuracy(&self.output()?, labels))
     }
 
-    pub fn train(&mut self) {
-        let mut best_train_acc: f32 = self.training_history.last().unwrap_or(&0.0).clone();
-        let mut best_test_acc: f32 = self.testing_history.last().unwrap_or(&0.0).clone();
-        for epoch in 0..self.epochs {
-            let pb = ProgressBar::new((self.data.trn_size / self.minibatch_size) as u64);
+    pub fn train(&mut self) -> Result<()> {
+        last_dense_layer(&self.layers)?;
+        self.check_minibatch_size()?;
+        let needs_validation = matches!(
+            self.saving_strategy,
+            SavingStrategy::BestValidationAccuracy(_)
+        ) || self
+            .early_stopping
+            .is_some_and(|policy| policy.metric.needs_validation())
+            || self
+                .checkpoint_retention
+                .is_some_and(|retention| retention.needs_validation());
+        if self.data.trn_size == 0 {
+            return Err(Error::Dataset(String::from(
+                "There is no training data, supply it with set_data",
+            )));
+        }
+        if needs_validation && self.data.val_size == 0 {
+            return Err(Error::Dataset(String::from(
+                "Selecting on validation metrics needs a validation set",
+            )));
+        }
+        let needs_test = matches!(self.saving_strategy, SavingStrategy::BestTestingAccuracy(_))
+            || self
+                .early_stopping
+                .is_some_and(|policy| policy.metric.needs_test())
+            || self
+                .checkpoint_retention
+                .is_some_and(|retention| retention.needs_test());
+        if needs_test && self.data.tst_size == 0 {
+            return Err(Error::Dataset(String::from(
+                "Selecting on test metrics needs a test set",
+            )));
+        }
+        self.check_set_sizes()?;
+        for labels in [&self.data.trn_lbl, &self.data.val_lbl, &self.data.tst_lbl] {
+            self.class_indices(labels)?;
+        }
+
+        if self.progress.is_none() {
+            let mut progress = TrainingProgress::new(self.early_stopping);
+            progress.best_train_acc = *self.training_history.last().unwrap_or(&0.0);
+            progress.best_test_acc = *self.testing_history.last().unwrap_or(&0.0);
+            progress.best_val_acc = *self.validation_history.last().unwrap_or(&0.0);
+            self.progress = Some(progress);
+        }
+        // State at the end of the last epoch that stayed finite, for rolling back
+        let mut last_good = (self.layers.clone(), self.scheduler.clone());
+        let epochs = self.epochs;
+        while !self.progress().finished(epochs) {
+            let epoch = self.progress().epoch;
+            // Every training image is visited once per epoch, in a fresh order. A resumed
+            // epoch keeps the order it was started with.
+            if self.progress().batches.is_empty() {
+                let batches = epoch_batches(self.data.trn_size, self.minibatch_size, &mut self.rng);
+                self.progress().batches = batches;
+            }
+            let batches = self.progress().batches.clone();
+            let num_batches = batches.len();
+            let pb = ProgressBar::new(num_batches as u64);
             if self.verbose {
                 pb.set_style(ProgressStyle::default_bar()
-                    .template(&format!("Epoch {}: [{{bar:.cyan/blue}}] {{pos}}/{{len}} - ETA: {{eta}} - acc: {{msg}}", epoch))
+                    .template(&format!("Epoch {}: [{{bar:.cyan/blue}}] {{pos}}/{{len}} - ETA: {{eta}} - {{msg}}", epoch))
                     .unwrap()
                     .progress_chars("#>-"));
+                pb.set_position(self.progress().batch as u64);
             }
 
-            let mut avg_acc = 0.0;
-            for i in 0..self.data.trn_size {
-                let (image, label) = get_random_image(&self.data);
-                let label = *self.data.classes.get(&label).unwrap();
-                self.forward_propagate(image, true);
-                self.back_propagate(label, true);
-
-                avg_acc += self.get_accuracy(label);
-
-                if i % self.minibatch_size == self.minibatch_size - 1 {
-                    self.update(self.minibatch_size);
+            let mut diverged = None;
+            let start = self.progress().batch;
+            for (i, indices) in batches.iter().enumerate().skip(start) {
+                let (images, labels) = get_batch(&self.data, indices)?;
+                let labels = self.class_indices(&labels)?;
+                let lr = self
+                    .scheduler
+                    .learning_rate(self.optimizer.learning_rate(), num_batches);
+                self.set_learning_rate(lr);
+                let (batch_acc, batch_loss) = self.train_batch(images, &labels)?;
+                // The last batch of an epoch can be smaller than the minibatch size
+                let norm = self.update(labels.len());
+                self.progress()
+                    .record_batch(batch_acc, batch_loss, labels.len(), norm);
+                self.scheduler.end_step();
+                if let Err(error) = self.check_divergence() {
+                    diverged = Some(error);
+                    break;
+                }
 
-                    if self.verbose {
-                        pb.inc(1);
-                        pb.set_message(format!("{:.1}%", avg_acc / (i + 1) as f32 * 100.0));
+                if self.verbose {
+                    let (avg_acc, avg_loss, _) = self.progress().epoch_means();
+                    pb.inc(1);
+                    pb.set_message(format!(
+                        "acc: {:.1}% - loss: {:.4}",
+                        avg_acc * 100.0,
+                        avg_loss
+                    ));
+                }
+                if let SavingStrategy::EveryNthEpoch(full_save, n) = self.saving_strategy {
+                    // n is a fraction of an epoch, so save every num_batches * n minibatches
+                    let every_n = ((num_batches as f32 * n) as usize).max(1);
+                    if i % every_n == every_n - 1 {
+                        self.save(full_save)?;
                     }
                 }
-                match self.saving_strategy {
-                    SavingStrategy::EveryNthEpoch(full_save, n) => {
-                        // n is an f32, so save every trn_size / minibatch_size * n iterations
-                        let every_n = (self.data.trn_size as f32 * n) as usize;
-                        if i % every_n == every_n - 1 {
-                            self.save(full_save);
+            }
+
+            if let Some(error) = diverged {
+                // Either way the epoch starts over, should training carry on
+                self.progress().restart_epoch();
+                let rollbacks = self.progress().rollbacks;
+                match self.divergence_guard.action {
+                    DivergenceAction::Rollback(max_rollbacks) if rollbacks < max_rollbacks => {
+                        self.progress().rollbacks += 1;
+                        if self.verbose {
+                            pb.abandon_with_message(format!(
+                                "{} - rolling back to the last good epoch",
+                                error
+                            ));
                         }
+                        (self.layers, self.scheduler) = last_good.clone();
+                        continue;
+                    }
+                    action => {
+                        if self.verbose {
+                            pb.abandon_with_message(error.to_string());
+                        }
+                        // Out of rollbacks, leave the model at its last good state
+                        if let DivergenceAction::Rollback(_) = action {
+                            (self.layers, self.scheduler) = last_good;
+                        }
+                        return Err(error);
                     }
-                    _ => {}
                 }
             }
 
-            avg_acc /= self.data.trn_size as f32;
+            let (avg_acc, avg_loss, avg_norm) = self.progress().epoch_means();
             if self.verbose {
-                pb.set_message(format!("{:.1}% - Testing...", avg_acc));
-            }
-
-            // Testing
-            let mut avg_test_acc = 0.0;
-            for _i in 0..self.data.tst_size {
-                // let image: Array3<f32> = self.data.tst_img[i].clone();
-                let (image, label) = get_random_test_image(&self.data);
-                let label = *self.data.classes.get(&label).unwrap();
-                self.forward_propagate(image, false);
-
-                avg_test_acc += self.get_accuracy(label); //self.data.tst_lbl[i] as usize);
+                pb.set_message(format!(
+                    "acc: {:.1}% - loss: {:.4} - Testing...",
+                    avg_acc * 100.0,
+                    avg_loss
+                ));
             }
 
-            avg_test_acc /= self.data.tst_size as f32;
+            // Validation and testing
+            let validation = self.validation_metrics()?;
+            let test = self.test_metrics()?;
             if self.verbose {
+                let validation_message = match validation {
+                    Some((val_acc, val_loss)) => format!(
+                        " - Val acc: {:.1}% - Val loss: {:.4}",
+                        val_acc * 100.0,
+                        val_loss
+                    ),
+                    None => String::new(),
+                };
+                let test_message = match test {
+                    Some((test_acc, test_loss)) => format!(
+                        " - Test acc: {:.1}% - Test loss: {:.4}",
+                        test_acc * 100.0,
+                        test_loss
+                    ),
+                    None => String::new(),
+                };
                 pb.finish_with_message(format!(
-                    "{:.1}% - Test: {:.1}%",
+                    "acc: {:.1}% - loss: {:.4}{}{}",
                     avg_acc * 100.0,
-                    avg_test_acc * 100.0
+                    avg_loss,
+                    validation_message,
+                    test_message
                 ));
             }
 
             self.training_history.push(avg_acc);
-            self.testing_history.push(avg_test_acc);
+            self.training_loss_history.push(avg_loss);
+            self.gradient_norm_history.push(avg_norm);
+            if let Some((test_acc, test_loss)) = test {
+                self.testing_history.push(test_acc);
+                self.testing_loss_history.push(test_loss);
+            }
+            if let Some((val_acc, val_loss)) = validation {
+                self.validation_history.push(val_acc);
+                self.validation_loss_history.push(val_loss);
+            }
+            // Plateaus are judged on the validation set when there is one
+            self.scheduler
+                .end_epoch(validation.or(test).map(|(acc, _)| acc));
             let duration = SystemTime::now()
                 .duration_since(self.creation_time)
                 .unwrap();
             self.time_history.push(duration.as_secs() as usize);
+            self.progress().end_epoch();
+
+            let metrics = EpochMetrics {
+                train_acc: avg_acc,
+                train_loss: avg_loss,
+                validation,
+                test,
+            };
+            let progress = self.progress.as_mut().unwrap();
+            if let Some(stopper) = &mut progress.stopper {
+                // Validation and test metrics were checked to be available before training
+                let metric = stopper.policy.metric.value(&metrics).unwrap_or(f32::NAN);
+                if stopper.record(epoch, metric) && stopper.policy.restore_best {
+                    progress.best_layers = Some(self.layers.clone());
+                }
+                if stopper.should_stop() && self.verbose {
+                    println!(
+                        "Early stopping after epoch {}, {:?} has not improved since epoch {}",
+                        epoch, stopper.policy.metric, stopper.best_epoch
+                    );
+                }
+            }
 
             match self.saving_strategy {
                 SavingStrategy::EveryEpoch(full_save) => {
-                    self.save(full_save);
+                    self.save(full_save)?;
                 }
                 SavingStrategy::BestTrainingAccuracy(full_save) => {
-                    if avg_acc > best_train_acc {
-                        best_train_acc = avg_acc;
-                        self.save(full_save);
+                    if avg_acc > self.progress().best_train_acc {
+                        self.progress().best_train_acc = avg_acc;
+                        self.save(full_save)?;
                     } else {
                         // If the accuracy is not improving, save the metadata anyway
-                        self.save(false);
+                        self.save(false)?;
                     }
                 }
                 SavingStrategy::BestTestingAccuracy(full_save) => {
-                    if avg_test_acc > best_test_acc {
-                        best_test_acc = avg_test_acc;
-                        self.save(full_save);
+                    let test_acc = test.map_or(0.0, |(test_acc, _)| test_acc);
+                    if test_acc > self.progress().best_test_acc {
+                        self.progress().best_test_acc = test_acc;
+                        self.save(full_save)?;
+                    } else {
+                        // If the accuracy is not improving, save the metadata anyway
+                        self.save(false)?;
+                    }
+                }
+                SavingStrategy::BestValidationAccuracy(full_save) => {
+                    let val_acc = validation.map_or(0.0, |(val_acc, _)| val_acc);
+                    if val_acc > self.progress().best_val_acc {
+                        self.progress().best_val_acc = val_acc;
+                        self.save(full_save)?;
                     } else {
                         // If the accuracy is not improving, save the metadata anyway
-                        self.save(false);
+                        self.save(false)?;
                     }
                 }
                 _ => {}
             }
+
+            last_good = (self.layers.clone(), self.scheduler.clone());
+
+            if let Some(retention) = self.checkpoint_retention {
+                self.write_checkpoint(retention, &metrics)?;
+            }
         }
+
+        // Whether stopped early or not, finish on the best layers when asked to
+        let progress = self.progress.take().unwrap();
+        if let Some(layers) = progress.best_layers {
+            self.layers = layers;
+        }
+
+        Ok(())
     }
 
-    pub fn zero(&mut self) {
-        for layer in &mut self.layers {
-            match layer {
-                Layer::Conv(conv_layer) => conv_layer.zero(),
-                Layer::Mxpl(mxpl_layer) => mxpl_layer.zero(),
-                Layer::Dense(dense_layer) => dense_layer.zero(),
-            }
+    /// Progress of the run `train` is in the middle of
+    fn progress(&mut self) -> &mut TrainingProgress {
+        self.progress.as_mut().unwrap()
+    }
+
+    /// Checks the last minibatch for NaN or infinite outputs, if enabled, and the weights
+    fn check_divergence(&mut self) -> Result<()> {
+        if let Some(layer) = self.non_finite_output.take() {
+            return Err(self.non_finite(layer, "output"));
         }
+        self.check_finite()
     }
 
-    pub fn save(&self, full_save: bool) {
-        std::fs::create_dir_all("models").unwrap();
-        let time_str = self
-            .creation_time
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
+    pub fn zero(&mut self) {
+        zero_layers(&mut self.layers);
+    }
+
+    /// Writes the model files named by the output config, each one atomically
+    pub fn save(&self, full_save: bool) -> Result<()> {
+        // Never write a poisoned model
+        self.check_finite()?;
 
         if full_save {
             // Save as JSON
-            let model_file_name = format!("models/{}_{}.json", self.name, time_str);
-            let model_file = File::create(&model_file_name).unwrap();
-            serde_json::to_writer(model_file, &self).unwrap();
+            write_atomically(&self.model_path("json"), |file| {
+                Ok(serde_json::to_writer(file, &self)?)
+            })?;
 
             // Save as binary
-            let binary_file_name = format!("models/model.bin");
-            let binary_file = File::create(&binary_file_name).unwrap();
-            bincode::serialize_into(binary_file, &self).unwrap();
+            write_atomically(&self.model_path("bin"), |file| {
+                Ok(bincode::serialize_into(file, &self)?)
+            })?;
         }
 
         // Write metadata to a text file
-        let metadata_file_name = format!("models/model.txt");
-        let mut metadata_file = File::create(&metadata_file_name).unwrap();
-        write!(metadata_file, "{:?}", self).unwrap();
+        write_atomically(&self.model_path("txt"), |file| {
+            Ok(write!(file, "{:?}", self)?)
+        })
+    }
+
+    /// Writes the whole model, along with the progress of the run it is in, so that
+    /// `CNN::resume` can finish the run
+    pub fn save_checkpoint<P: AsRef<Path>>(&self, checkpoint_file_name: P) -> Result<()> {
+        self.check_finite()?;
+        let checkpoint = CheckpointRef {
+            model: self,
+            progress: &self.progress,
+        };
+        write_atomically(checkpoint_file_name.as_ref(), |file| {
+            Ok(serde_json::to_writer(file, &checkpoint)?)
+        })
+    }
+
+    /// Writes the checkpoint of the epoch just trained and deletes the ones `retention` no
+    /// longer keeps
+    fn write_checkpoint(&mut self, retention: Retention, metrics: &EpochMetrics) -> Result<()> {
+        let epoch = self.training_history.len();
+        let path = self
+            .output
+            .checkpoint_path(&self.name, self.creation_millis(), epoch);
+        // A template without the epoch overwrites the same file every time
+        self.checkpoints
+            .retain(|checkpoint| checkpoint.path != path);
+        self.checkpoints.push(Checkpoint {
+            path: path.clone(),
+            epoch,
+            metric: retention.metric.value(metrics),
+        });
+        let removed = retention.prune(&mut self.checkpoints);
+        // Written after pruning, so a resumed run knows which files are left
+        self.save_checkpoint(&path)?;
+        for checkpoint in removed {
+            match std::fs::remove_file(&checkpoint.path) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Path of the model file with the given extension
+    fn model_path(&self, extension: &str) -> PathBuf {
+        self.output.model_path(
+            &self.name,
+            self.creation_millis(),
+            self.training_history.len(),
+            extension,
+        )
+    }
+
+    /// Creation time in milliseconds since the Unix epoch, which names the saved files
+    fn creation_millis(&self) -> u128 {
+        self.creation_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    /// Maps dataset labels to output indices
+    fn class_indices(&self, labels: &[usize]) -> Result<Vec<usize>> {
+        labels
+            .iter()
+            .map(|label| {
+                self.data.classes.get(label).copied().ok_or_else(|| {
+                    Error::Dataset(format!("Label {} is not one of the classes", label))
+                })
+            })
+            .collect()
+    }
+
+    /// The sizes of the training, validation and test sets have to match their images and
+    /// labels, as batches are drawn by index up to the size
+    fn check_set_sizes(&self) -> Result<()> {
+        let data = &self.data;
+        let sets = [
+            (
+                "training",
+                data.trn_size,
+                data.trn_img.len(),
+                data.trn_lbl.len(),
+            ),
+            (
+                "validation",
+                data.val_size,
+                data.val_img.len(),
+                data.val_lbl.len(),
+            ),
+            (
+                "test",
+                data.tst_size,
+                data.tst_img.len(),
+                data.tst_lbl.len(),
+            ),
+        ];
+        for (set, size, images, labels) in sets {
+            if images != size || labels != size {
+                return Err(Error::Dataset(format!(
+                    "The {} set has size {} but {} images and {} labels",
+                    set, size, images, labels
+                )));
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn test(&mut self) {
-        let mut avg_test_acc = 0.0;
-        for _i in 0..self.data.tst_size {
-            let (image, label) = get_random_test_image(&self.data);
-            let label = *self.data.classes.get(&label).unwrap();
-            self.forward_propagate(image, false);
+    /// Batching needs at least one sample per minibatch
+    fn check_minibatch_size(&self) -> Result<()> {
+        if self.minibatch_size == 0 {
+            return Err(Error::Dataset(String::from(
+                "The minibatch size must be at least 1",
+            )));
+        }
 
-            avg_test_acc += self.get_accuracy(label); //self.data.tst_lbl[i] as usize);
+        Ok(())
+    }
+
+    /// The pool used for parallel training and evaluation, built on first use. It is taken
+    /// out of `self` so the layers can be borrowed while it runs, and put back afterwards.
+    fn take_thread_pool(&mut self) -> ThreadPool {
+        match self.thread_pool.take() {
+            Some(pool) => pool,
+            None => ThreadPoolBuilder::new()
+                .num_threads(self.threads)
+                .build()
+                .unwrap(),
         }
+    }
 
-        avg_test_acc /= self.data.tst_size as f32;
-        println!("Test accuracy: {:.1}%", avg_test_acc * 100.0);
+    /// Accuracy and mean loss over every test image, in order, with the minibatches
+    /// evaluated in parallel, None when there is no test set
+    fn test_metrics(&mut self) -> Result<Option<(f32, f32)>> {
+        if self.data.tst_size == 0 {
+            return Ok(None);
+        }
+        self.evaluate(self.data.tst_size, get_test_batch).map(Some)
     }
+
+    /// Mean accuracy and loss over the whole validation set, None when there is none
+    fn validation_metrics(&mut self) -> Result<Option<(f32, f32)>> {
+        if self.data.val_size == 0 {
+            return Ok(None);
+        }
+        self.evaluate(self.data.val_size, get_validation_batch)
+            .map(Some)
+    }
+
+    /// Mean accuracy and loss over the `size` samples fetched by `get_batch`
+    fn evaluate(&mut self, size: usize, get_batch: BatchFetcher) -> Result<(f32, f32)> {
+        self.check_minibatch_size()?;
+        self.check_set_sizes()?;
+        if size == 0 {
+            return Err(Error::Dataset(String::from(
+                "There are no samples to evaluate",
+            )));
+        }
+        let batches = ordered_batches(size, self.minibatch_size)
+            .iter()
+            .map(|indices| {
+                let (images, labels) = get_batch(&self.data, indices)?;
+                Ok((images, self.class_indices(&labels)?))
+            })
+            .collect::<Result<Vec<(Array4<f32>, Vec<usize>)>>>()?;
+
+        let pool = self.take_thread_pool();
+        let totals: Vec<(f32, f32)> = pool.install(|| {
+            batches
+                .into_par_iter()
+                .map(|(images, labels)| {
+                    let output = self.predict(images);
+                    let size = labels.len() as f32;
+                    (
+                        accuracy(&output, &labels) * size,
+                        self.loss.loss(&output, &labels) * size,
+                    )
+                })
+                .collect()
+        });
+        self.thread_pool = Some(pool);
+
+        let (accuracy, loss) = mean_metrics(&totals, size);
+        Ok((accuracy, loss + self.regularization_loss()))
+    }
+
+    pub fn test(&mut self) -> Result<()> {
+        // Saved models leave out their data, so a loaded one has nothing to test on yet
+        if self.data.tst_size == 0 {
+            return Err(Error::Dataset(String::from(
+                "There is no test data, supply it with set_data",
+            )));
+        }
+        let (avg_test_acc, avg_test_loss) = self.evaluate(self.data.tst_size, get_test_batch)?;
+        println!(
+            "Test accuracy: {:.1}% - Test loss: {:.4}",
+            avg_test_acc * 100.0,
+            avg_test_loss
+        );
+
+        Ok(())
+    }
+}
+
+/// Checks that a `kernel_size` window moved by `stride` fits inside the padded input
+fn check_window(
+    layer_name: &str,
+    input_size: (usize, usize, usize),
+    kernel_size: (usize, usize),
+    stride: (usize, usize),
+    padding: Padding,
+) -> Result<()> {
+    if kernel_size.0 == 0 || kernel_size.1 == 0 || stride.0 == 0 || stride.1 == 0 {
+        return Err(Error::InvalidLayer(format!(
+            "{} kernel size and stride must be positive",
+            layer_name
+        )));
+    }
+    let (pad_x, pad_y) = (
+        padding.amounts(input_size.0, kernel_size.0, stride.0),
+        padding.amounts(input_size.1, kernel_size.1, stride.1),
+    );
+    let padded = (
+        input_size.0 + pad_x.0 + pad_x.1,
+        input_size.1 + pad_y.0 + pad_y.1,
+    );
+    if kernel_size.0 > padded.0 || kernel_size.1 > padded.1 {
+        return Err(Error::InvalidLayer(format!(
+            "{} kernel {}x{} does not fit its {}x{} input",
+            layer_name, kernel_size.0, kernel_size.1, padded.0, padded.1
+        )));
+    }
+
+    Ok(())
+}
+
+/// Clears the gradients and caches of every layer
+fn zero_layers(layers: &mut [Layer]) {
+    for layer in layers {
+        match layer {
+            Layer::Conv(conv_layer) => conv_layer.zero(),
+            Layer::Mxpl(mxpl_layer) => mxpl_layer.zero(),
+            Layer::Avpl(avpl_layer) => avpl_layer.zero(),
+            Layer::GlobalAvpl(global_avpl_layer) => global_avpl_layer.zero(),
+            Layer::Dense(dense_layer) => dense_layer.zero(),
+        }
+    }
+}
+
+/// Forwards a batch through `layers`, returning one output row per image
+fn forward_layers(layers: &mut [Layer], images: Array4<f32>, training: bool) -> Array2<f32> {
+    let mut output: Array4<f32> = images;
+    let mut flat_output: Array2<f32> = flatten_batch(output.clone());
+    for layer in layers {
+        match layer {
+            Layer::Conv(conv_layer) => {
+                output = conv_layer.forward_propagate(output);
+                flat_output = flatten_batch(output.clone());
+            }
+            Layer::Mxpl(mxpl_layer) => {
+                output = mxpl_layer.forward_propagate(output);
+                flat_output = flatten_batch(output.clone());
+            }
+            Layer::Avpl(avpl_layer) => {
+                output = avpl_layer.forward_propagate(output);
+                flat_output = flatten_batch(output.clone());
+            }
+            Layer::GlobalAvpl(global_avpl_layer) => {
+                output = global_avpl_layer.forward_propagate(output);
+                flat_output = flatten_batch(output.clone());
+            }
+            Layer::Dense(dense_layer) => {
+                flat_output = dense_layer.forward_propagate(flat_output, training);
+            }
+        }
+    }
+
+    flat_output
+}
+
+/// Forwards a batch through `layers` without caching anything for back propagation
+fn infer_layers(layers: &[Layer], images: Array4<f32>) -> Array2<f32> {
+    let mut output: Array4<f32> = images;
+    let mut flat_output: Array2<f32> = flatten_batch(output.clone());
+    for layer in layers {
+        match layer {
+            Layer::Conv(conv_layer) => {
+                output = conv_layer.infer(output);
+                flat_output = flatten_batch(output.clone());
+            }
+            Layer::Mxpl(mxpl_layer) => {
+                output = mxpl_layer.infer(output);
+                flat_output = flatten_batch(output.clone());
+            }
+            Layer::Avpl(avpl_layer) => {
+                output = avpl_layer.infer(output);
+                flat_output = flatten_batch(output.clone());
+            }
+            Layer::GlobalAvpl(global_avpl_layer) => {
+                output = global_avpl_layer.infer(output);
+                flat_output = flatten_batch(output.clone());
+            }
+            Layer::Dense(dense_layer) => {
+                flat_output = dense_layer.infer(&flat_output);
+            }
+        }
+    }
+
+    flat_output
+}
+
+/// The Dense Layer at the end of `layers` whose outputs are the class scores
+fn last_dense_layer(layers: &[Layer]) -> Result<&DenseLayer> {
+    match layers.last() {
+        Some(Layer::Dense(dense_layer)) => Ok(dense_layer),
+        _ => Err(Error::InvalidLayer(String::from(
+            "The last layer must be a Dense Layer to train",
+        ))),
+    }
+}
+
+/// Error on the logits of the last layer for the last forwarded batch
+fn output_error(layers: &[Layer], labels: &[usize], loss: Loss) -> Result<Array2<f32>> {
+    let dense_layer = last_dense_layer(layers)?;
+    Ok(loss.output_error(&dense_layer.output, labels, dense_layer.activation))
+}
+
+/// Back-propagates the error on the last layer's logits through `layers`
+fn back_propagate_layers(layers: &mut [Layer], mut flat_error: Array2<f32>, training: bool) {
+    let batch_size = flat_error.nrows();
+    let mut error: Array4<f32> = flat_error
+        .clone()
+        .into_shape_with_order((batch_size, 1, 1, flat_error.ncols()))
+        .unwrap();
+    for (i, layer) in layers.iter_mut().rev().enumerate() {
+        match layer {
+            Layer::Conv(conv_layer) => {
+                error = conv_layer.back_propagate(error);
+            }
+            Layer::Mxpl(mxpl_layer) => {
+                error = mxpl_layer.back_propagate(error);
+            }
+            Layer::Avpl(avpl_layer) => {
+                error = avpl_layer.back_propagate(error);
+            }
+            Layer::GlobalAvpl(global_avpl_layer) => {
+                error = global_avpl_layer.back_propagate(error);
+            }
+            Layer::Dense(dense_layer) => {
+                // The loss has already gone through the last layer's activation
+                flat_error = if i == 0 {
+                    dense_layer.back_propagate_logits(flat_error, training)
+                } else {
+                    dense_layer.back_propagate(flat_error, training)
+                };
+                let (tx, ty, tc) = dense_layer.transition_shape;
+                error = flat_error
+                    .clone()
+                    .into_shape_with_order((batch_size, tx, ty, tc))
+                    .unwrap();
+            }
+        }
+    }
+}
+
+/// Index of the first convolutional or dense layer whose last outputs are not all finite
+fn non_finite_output(layers: &[Layer]) -> Option<usize> {
+    layers.iter().position(|layer| match layer {
+        Layer::Conv(conv_layer) => !conv_layer.output_is_finite(),
+        Layer::Dense(dense_layer) => !dense_layer.output_is_finite(),
+        _ => false,
+    })
+}
+
+/// Fraction of `output` rows whose highest value matches its label
+fn accuracy(output: &Array2<f32>, labels: &[usize]) -> f32 {
+    let mut correct = 0;
+    for (row, &label) in output.rows().into_iter().zip(labels) {
+        let mut max = f32::NEG_INFINITY;
+        let mut max_idx = 0;
+        for (j, &value) in row.iter().enumerate() {
+            if value > max {
+                max = value;
+                max_idx = j;
+            }
+        }
+        correct += (max_idx == label) as usize;
+    }
+
+    correct as f32 / labels.len() as f32
+}
+
+/// Averages per-chunk (accuracy, loss) totals, each already weighted by its chunk size
+fn mean_metrics(totals: &[(f32, f32)], size: usize) -> (f32, f32) {
+    let (correct, loss) = totals
+        .iter()
+        .fold((0.0, 0.0), |(a, l), (ta, tl)| (a + ta, l + tl));
+
+    (correct / size as f32, loss / size as f32)
+}
+
+/// Flattens a (batch, x, y, channels) tensor into one row per sample
+fn flatten_batch(x: Array4<f32>) -> Array2<f32> {
+    let (n, a, b, c) = x.dim();
+    x.into_shape_with_order((n, a * b * c)).unwrap()
 }
@@ -1,25 +1,84 @@
-use crate::optimizer::{Optimizer4D, OptimizerAlg};
-use ndarray::{s, Array3, Array4};
+use crate::activation::{backward_batch_error, forward_batch, Activation};
+use crate::clipping::GradientClipping;
+use crate::divergence::all_finite;
+use crate::optimizer::{Optimizer1D, Optimizer4D, OptimizerAlg};
+use crate::regularization::Regularization;
+use crate::utils::deserialize_pair;
+use ndarray::{s, Array1, Array2, Array4, Array6, ArrayView2, Axis};
+use rand::Rng;
 use rand_distr::{Distribution, Normal};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Formatter};
-use std::ops::{AddAssign, SubAssign};
+use std::ops::AddAssign;
 
-#[derive(Serialize, Deserialize)]
+/// Zero-padding applied around the input of a `ConvLayer`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Padding {
+    /// No padding, the kernel only visits positions that lie fully inside the input.
+    #[default]
+    Valid,
+    /// Pads just enough for the output to be `ceil(input / stride)` wide.
+    Same,
+    /// Pads every border with the given number of zeros.
+    Explicit(usize),
+}
+
+impl Padding {
+    /// Returns the number of zeros added before and after an axis of length `input`.
+    pub fn amounts(&self, input: usize, kernel_size: usize, stride: usize) -> (usize, usize) {
+        match self {
+            Padding::Valid => (0, 0),
+            Padding::Same => {
+                let output = input.div_ceil(stride);
+                let total = ((output - 1) * stride + kernel_size).saturating_sub(input);
+                (total / 2, total - total / 2)
+            }
+            Padding::Explicit(amount) => (*amount, *amount),
+        }
+    }
+}
+
+/// Models saved before conv activations were configurable always used ReLU
+fn default_activation() -> Activation {
+    Activation::Relu
+}
+
+/// Prepends the batch dimension to a per-sample shape
+fn batch_shape(batch_size: usize, size: (usize, usize, usize)) -> (usize, usize, usize, usize) {
+    (batch_size, size.0, size.1, size.2)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ConvLayer {
     pub input_size: (usize, usize, usize),
-    pub kernel_size: usize,
+    #[serde(deserialize_with = "deserialize_pair")]
+    pub kernel_size: (usize, usize),
     pub output_size: (usize, usize, usize),
     #[serde(skip)]
-    pub input: Array3<f32>,
+    pub input: Array4<f32>,
     #[serde(skip)]
-    pub output: Array3<f32>,
-    pub stride: usize,
+    pub output: Array4<f32>,
+    #[serde(skip)]
+    pub columns: Array2<f32>,
+    #[serde(deserialize_with = "deserialize_pair")]
+    pub stride: (usize, usize),
+    #[serde(default)]
+    pub padding: Padding,
     pub num_filters: usize,
+    #[serde(default = "default_activation")]
+    pub activation: Activation,
     pub kernels: Array4<f32>,
+    #[serde(default)]
+    pub biases: Array1<f32>,
     #[serde(skip)]
     pub kernel_changes: Array4<f32>,
+    #[serde(skip)]
+    pub bias_changes: Array1<f32>,
     pub optimizer: Optimizer4D,
+    #[serde(default)]
+    pub bias_optimizer: Optimizer1D,
+    #[serde(default)]
+    pub regularization: Regularization,
 }
 
 impl Debug for ConvLayer {
@@ -32,14 +91,17 @@ impl Debug for ConvLayer {
         ));
         s.push_str(&format!(
             "Kernel Size: {}x{}\n",
-            self.kernel_size, self.kernel_size
+            self.kernel_size.0, self.kernel_size.1
         ));
         s.push_str(&format!(
             "Output Size: {}x{}x{}\n",
             self.output_size.0, self.output_size.1, self.output_size.2
         ));
-        s.push_str(&format!("Stride: {}\n", self.stride));
+        s.push_str(&format!("Stride: {}x{}\n", self.stride.0, self.stride.1));
+        s.push_str(&format!("Padding: {:?}\n", self.padding));
         s.push_str(&format!("Number of Filters: {}\n", self.num_filters));
+        s.push_str(&format!("Activation: {:?}\n", self.activation));
+        s.push_str(&format!("Regularization: {:?}\n", self.regularization));
 
         write!(f, "{}", s)
     }
@@ -47,116 +109,462 @@ impl Debug for ConvLayer {
 
 impl ConvLayer {
     pub fn zero(&mut self) {
-        self.kernel_changes = Array4::<f32>::zeros((
-            self.num_filters,
-            self.kernel_size,
-            self.kernel_size,
-            self.input_size.2,
-        ));
-        self.output = Array3::<f32>::zeros(self.output_size);
+        self.kernel_changes = Array4::<f32>::zeros(self.kernels.dim());
+        self.bias_changes = Array1::<f32>::zeros(self.num_filters);
+        // Models saved before biases were introduced load with an empty bias vector
+        if self.biases.len() != self.num_filters {
+            self.biases = Array1::<f32>::zeros(self.num_filters);
+        }
+        // Models saved before biases had their own optimizer state load without one
+        if self.bias_optimizer.momentum1.len() != self.num_filters {
+            self.bias_optimizer =
+                Optimizer1D::new(self.optimizer.alg.without_weight_decay(), self.num_filters);
+        }
+        self.output = Array4::<f32>::zeros(batch_shape(0, self.output_size));
     }
 
-    /// Create a new max pooling layer with the given parameters
+    /// Create a new convolutional layer with the given parameters
     pub fn new(
         input_size: (usize, usize, usize),
-        kernel_size: usize,
-        stride: usize,
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+        padding: Padding,
         num_filters: usize,
+        activation: Activation,
         optimizer_alg: OptimizerAlg,
     ) -> ConvLayer {
-        let output_width: usize = ((input_size.0 - kernel_size) / stride) + 1;
-        let output_size = (output_width, output_width, num_filters);
-        let mut kernels =
-            Array4::<f32>::zeros((num_filters, kernel_size, kernel_size, input_size.2));
+        ConvLayer::new_with_rng(
+            input_size,
+            kernel_size,
+            stride,
+            padding,
+            num_filters,
+            activation,
+            optimizer_alg,
+            &mut rand::thread_rng(),
+        )
+    }
+
+    /// Same as `new`, drawing the initial kernels from `rng` so they can be reproduced
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_rng<R: Rng>(
+        input_size: (usize, usize, usize),
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+        padding: Padding,
+        num_filters: usize,
+        activation: Activation,
+        optimizer_alg: OptimizerAlg,
+        rng: &mut R,
+    ) -> ConvLayer {
+        let (pad_x, pad_y) = (
+            padding.amounts(input_size.0, kernel_size.0, stride.0),
+            padding.amounts(input_size.1, kernel_size.1, stride.1),
+        );
+        let output_size = (
+            ((input_size.0 + pad_x.0 + pad_x.1 - kernel_size.0) / stride.0) + 1,
+            ((input_size.1 + pad_y.0 + pad_y.1 - kernel_size.1) / stride.1) + 1,
+            num_filters,
+        );
+        let kernel_shape = (num_filters, kernel_size.0, kernel_size.1, input_size.2);
+        let mut kernels = Array4::<f32>::zeros(kernel_shape);
         let normal = Normal::new(0.0, 1.0).unwrap();
 
         for f in 0..num_filters {
             for kd in 0..input_size.2 {
-                for ky in 0..kernel_size {
-                    for kx in 0..kernel_size {
-                        kernels[[f, ky, kx, kd]] = normal.sample(&mut rand::thread_rng())
-                            * (2.0 / (input_size.0.pow(2)) as f32).sqrt();
+                for kx in 0..kernel_size.0 {
+                    for ky in 0..kernel_size.1 {
+                        kernels[[f, kx, ky, kd]] = normal.sample(rng)
+                            * (2.0 / (input_size.0 * input_size.1) as f32).sqrt();
                     }
                 }
             }
         }
 
-        let optimizer = Optimizer4D::new(
-            optimizer_alg,
-            (num_filters, kernel_size, kernel_size, input_size.2),
-        );
+        let optimizer = Optimizer4D::new(optimizer_alg, kernel_shape);
+        let bias_optimizer = Optimizer1D::new(optimizer_alg.without_weight_decay(), num_filters);
 
         let layer: ConvLayer = ConvLayer {
             input_size,
             kernel_size,
             output_size,
             stride,
-            output: Array3::<f32>::zeros(output_size),
-            input: Array3::<f32>::zeros(input_size),
+            padding,
+            output: Array4::<f32>::zeros(batch_shape(0, output_size)),
+            input: Array4::<f32>::zeros(batch_shape(0, input_size)),
+            columns: Array2::<f32>::zeros((0, kernel_size.0 * kernel_size.1 * input_size.2)),
             num_filters,
+            activation,
             kernels,
-            kernel_changes: Array4::<f32>::zeros((
-                num_filters,
-                kernel_size,
-                kernel_size,
-                input_size.2,
-            )),
+            biases: Array1::<f32>::zeros(num_filters),
+            kernel_changes: Array4::<f32>::zeros(kernel_shape),
+            bias_changes: Array1::<f32>::zeros(num_filters),
             optimizer,
+            bias_optimizer,
+            regularization: Regularization::none(),
         };
 
         layer
     }
 
-    pub fn forward_propagate(&mut self, input: Array3<f32>) -> Array3<f32> {
-        self.input = input;
-        for f in 0..self.output_size.2 {
-            let kernel_slice = self.kernels.slice(s![f, .., .., ..]);
-            for y in 0..self.output_size.1 {
-                for x in 0..self.output_size.0 {
-                    let input_slice =
-                        self.input
-                            .slice(s![x..x + self.kernel_size, y..y + self.kernel_size, ..]);
-                    self.output[[x, y, f]] = (&input_slice * &kernel_slice).sum().max(0.0);
-                }
+    /// Zeros added before and after each spatial axis of the input
+    fn padding_amounts(&self) -> ((usize, usize), (usize, usize)) {
+        (
+            self.padding
+                .amounts(self.input_size.0, self.kernel_size.0, self.stride.0),
+            self.padding
+                .amounts(self.input_size.1, self.kernel_size.1, self.stride.1),
+        )
+    }
+
+    /// Size of the input once the zero-padding has been added
+    fn padded_size(&self) -> (usize, usize, usize) {
+        let (pad_x, pad_y) = self.padding_amounts();
+        (
+            self.input_size.0 + pad_x.0 + pad_x.1,
+            self.input_size.1 + pad_y.0 + pad_y.1,
+            self.input_size.2,
+        )
+    }
+
+    /// Copies a batch of inputs into the centre of a zero-padded tensor
+    fn pad(&self, input: Array4<f32>) -> Array4<f32> {
+        let (pad_x, pad_y) = self.padding_amounts();
+        let mut padded = Array4::<f32>::zeros(batch_shape(input.dim().0, self.padded_size()));
+        padded
+            .slice_mut(s![
+                ..,
+                pad_x.0..pad_x.0 + self.input_size.0,
+                pad_y.0..pad_y.0 + self.input_size.1,
+                ..
+            ])
+            .assign(&input);
+
+        padded
+    }
+
+    /// Drops the gradient that flowed into the zero-padding
+    fn unpad(&self, padded: Array4<f32>) -> Array4<f32> {
+        let (pad_x, pad_y) = self.padding_amounts();
+        padded
+            .slice(s![
+                ..,
+                pad_x.0..pad_x.0 + self.input_size.0,
+                pad_y.0..pad_y.0 + self.input_size.1,
+                ..
+            ])
+            .to_owned()
+    }
+
+    /// Kernels flattened to (filters, kernel_x * kernel_y * channels)
+    fn kernel_matrix(&self) -> ArrayView2<'_, f32> {
+        let window_len = self.kernel_size.0 * self.kernel_size.1 * self.input_size.2;
+        self.kernels
+            .view()
+            .into_shape_with_order((self.num_filters, window_len))
+            .unwrap()
+    }
+
+    /// Applies the activation to a batch of logits
+    fn activate(&self, logits: Array4<f32>) -> Array4<f32> {
+        let batch_size = logits.dim().0;
+        let output_len = self.output_size.0 * self.output_size.1 * self.output_size.2;
+        let logits: Array2<f32> = logits
+            .into_shape_with_order((batch_size, output_len))
+            .unwrap();
+
+        forward_batch(logits, self.activation)
+            .into_shape_with_order(batch_shape(batch_size, self.output_size))
+            .unwrap()
+    }
+
+    /// Convolves an im2col matrix with the kernels, giving the batch of logits
+    fn convolve(&self, columns: &Array2<f32>, batch_size: usize) -> Array4<f32> {
+        let logits = columns.dot(&self.kernel_matrix().t()) + &self.biases;
+        logits
+            .into_shape_with_order(batch_shape(batch_size, self.output_size))
+            .unwrap()
+    }
+
+    /// Turns the error on the output into the error on the logits
+    fn logits_error(&self, error: Array4<f32>) -> Array4<f32> {
+        let batch_size = error.dim().0;
+        let output_len = self.output_size.0 * self.output_size.1 * self.output_size.2;
+        let flat_output: Array2<f32> = self
+            .output
+            .clone()
+            .into_shape_with_order((batch_size, output_len))
+            .unwrap();
+        let flat_error: Array2<f32> = error
+            .into_shape_with_order((batch_size, output_len))
+            .unwrap();
+
+        backward_batch_error(&flat_output, flat_error, self.activation)
+            .into_shape_with_order(batch_shape(batch_size, self.output_size))
+            .unwrap()
+    }
+
+    /// Unrolls every receptive field of the padded input into one row, giving a
+    /// (batch * output_x * output_y, kernel_x * kernel_y * channels) matrix
+    fn im2col(&self, padded: &Array4<f32>) -> Array2<f32> {
+        let batch_size = padded.dim().0;
+        let (ox, oy, _) = self.output_size;
+        let (kx, ky) = self.kernel_size;
+        let (sx, sy) = self.stride;
+        let channels = self.input_size.2;
+
+        // Each kernel offset picks a strided grid of the input, one value per output position
+        let mut columns = Array6::<f32>::zeros((batch_size, ox, oy, kx, ky, channels));
+        for i in 0..kx {
+            for j in 0..ky {
+                columns
+                    .slice_mut(s![.., .., .., i, j, ..])
+                    .assign(&padded.slice(s![
+                        ..,
+                        i..i + sx * (ox - 1) + 1;sx,
+                        j..j + sy * (oy - 1) + 1;sy,
+                        ..
+                    ]));
             }
         }
 
+        columns
+            .into_shape_with_order((batch_size * ox * oy, kx * ky * channels))
+            .unwrap()
+    }
+
+    /// Inverse of `im2col`, summing every row back into the receptive field it came from
+    fn col2im(&self, columns: Array2<f32>, batch_size: usize) -> Array4<f32> {
+        let (ox, oy, _) = self.output_size;
+        let (kx, ky) = self.kernel_size;
+        let (sx, sy) = self.stride;
+        let columns = columns
+            .into_shape_with_order((batch_size, ox, oy, kx, ky, self.input_size.2))
+            .unwrap();
+
+        let mut padded = Array4::<f32>::zeros(batch_shape(batch_size, self.padded_size()));
+        for i in 0..kx {
+            for j in 0..ky {
+                padded
+                    .slice_mut(s![
+                        ..,
+                        i..i + sx * (ox - 1) + 1;sx,
+                        j..j + sy * (oy - 1) + 1;sy,
+                        ..
+                    ])
+                    .add_assign(&columns.slice(s![.., .., .., i, j, ..]));
+            }
+        }
+
+        padded
+    }
+
+    /// Forward a batch of inputs shaped (batch, x, y, channels) as a single
+    /// im2col matrix multiplication
+    pub fn forward_propagate(&mut self, input: Array4<f32>) -> Array4<f32> {
+        let batch_size = input.dim().0;
+        self.input = self.pad(input);
+        self.columns = self.im2col(&self.input);
+        self.output = self.activate(self.convolve(&self.columns, batch_size));
+
         self.output.clone()
     }
 
-    pub fn back_propagate(&mut self, error: Array3<f32>) -> Array3<f32> {
-        let mut prev_error: Array3<f32> = Array3::<f32>::zeros(self.input_size);
-        for f in 0..self.output_size.2 {
-            for y in 0..self.output_size.1 {
-                for x in 0..self.output_size.0 {
-                    if self.output[[x, y, f]] <= 0.0 {
-                        continue;
-                    }
-                    prev_error
-                        .slice_mut(s![x..x + self.kernel_size, y..y + self.kernel_size, ..])
-                        .add_assign(&(error[[x, y, f]] * &self.kernels.slice(s![f, .., .., ..])));
-
-                    let input_slice =
-                        self.input
-                            .slice(s![x..x + self.kernel_size, y..y + self.kernel_size, ..]);
-                    self.kernel_changes
-                        .slice_mut(s![f, .., .., ..])
-                        .sub_assign(&(error[[x, y, f]] * &input_slice));
-                }
+    /// Same as `forward_propagate` but leaves the layer untouched, so it can run
+    /// from several threads at once
+    pub fn infer(&self, input: Array4<f32>) -> Array4<f32> {
+        let batch_size = input.dim().0;
+        let columns = self.im2col(&self.pad(input));
+
+        self.activate(self.convolve(&columns, batch_size))
+    }
+
+    pub fn back_propagate(&mut self, error: Array4<f32>) -> Array4<f32> {
+        let batch_size = error.dim().0;
+        let error = self.logits_error(error);
+        let error = error
+            .into_shape_with_order((
+                batch_size * self.output_size.0 * self.output_size.1,
+                self.num_filters,
+            ))
+            .unwrap();
+
+        let kernel_gradients = error.t().dot(&self.columns);
+        self.kernel_changes -= &kernel_gradients
+            .into_shape_with_order(self.kernels.dim())
+            .unwrap();
+        self.bias_changes -= &error.sum_axis(Axis(0));
+
+        let prev_error = self.col2im(error.dot(&self.kernel_matrix()), batch_size);
+        self.unpad(prev_error)
+    }
+
+    /// Direct convolution that visits every output position in turn. Much slower
+    /// than `forward_propagate`, kept as a reference to verify it against.
+    pub fn forward_propagate_naive(&mut self, input: Array4<f32>) -> Array4<f32> {
+        let batch_size = input.dim().0;
+        self.input = self.pad(input);
+
+        let (kx, ky) = self.kernel_size;
+        let window_len = kx * ky * self.input_size.2;
+        let kernels = self.kernel_matrix();
+        let mut logits = Array4::<f32>::zeros(batch_shape(batch_size, self.output_size));
+        for y in 0..self.output_size.1 {
+            for x in 0..self.output_size.0 {
+                let (ix, iy) = (x * self.stride.0, y * self.stride.1);
+                let window = self.input.slice(s![.., ix..ix + kx, iy..iy + ky, ..]);
+                let window = window.to_shape((batch_size, window_len)).unwrap();
+                logits
+                    .slice_mut(s![.., x, y, ..])
+                    .assign(&(window.dot(&kernels.t()) + &self.biases));
+            }
+        }
+        self.output = self.activate(logits);
+
+        self.output.clone()
+    }
+
+    /// Back propagation matching `forward_propagate_naive`
+    pub fn back_propagate_naive(&mut self, error: Array4<f32>) -> Array4<f32> {
+        let batch_size = error.dim().0;
+        let error = self.logits_error(error);
+
+        let (kx, ky) = self.kernel_size;
+        let window_len = kx * ky * self.input_size.2;
+        let kernels = self.kernel_matrix();
+        let mut kernel_gradients = Array2::<f32>::zeros((self.num_filters, window_len));
+        let mut prev_error = Array4::<f32>::zeros(batch_shape(batch_size, self.padded_size()));
+        for y in 0..self.output_size.1 {
+            for x in 0..self.output_size.0 {
+                let (ix, iy) = (x * self.stride.0, y * self.stride.1);
+                let position_error = error.slice(s![.., x, y, ..]);
+                prev_error
+                    .slice_mut(s![.., ix..ix + kx, iy..iy + ky, ..])
+                    .add_assign(
+                        &position_error
+                            .dot(&kernels)
+                            .into_shape_with_order((batch_size, kx, ky, self.input_size.2))
+                            .unwrap(),
+                    );
+
+                let window = self.input.slice(s![.., ix..ix + kx, iy..iy + ky, ..]);
+                let window = window.to_shape((batch_size, window_len)).unwrap();
+                kernel_gradients += &position_error.t().dot(&window);
             }
         }
+        self.kernel_changes -= &kernel_gradients
+            .into_shape_with_order(self.kernels.dim())
+            .unwrap();
+        self.bias_changes -= &error.sum_axis(Axis(0)).sum_axis(Axis(0)).sum_axis(Axis(0));
+
+        self.unpad(prev_error)
+    }
+
+    /// Adds the gradients accumulated by another copy of this layer, e.g. a worker replica
+    pub fn accumulate_gradients(&mut self, other: &ConvLayer) {
+        self.kernel_changes += &other.kernel_changes;
+        self.bias_changes += &other.bias_changes;
+    }
+
+    /// A copy for a worker thread to run part of a minibatch on. It leaves out the optimizer
+    /// state, which only the original layer needs for its update.
+    pub fn replica(&self) -> ConvLayer {
+        ConvLayer {
+            input_size: self.input_size,
+            kernel_size: self.kernel_size,
+            output_size: self.output_size,
+            input: Array4::<f32>::zeros(batch_shape(0, self.input_size)),
+            output: Array4::<f32>::zeros(batch_shape(0, self.output_size)),
+            columns: Array2::<f32>::zeros((0, self.columns.ncols())),
+            stride: self.stride,
+            padding: self.padding,
+            num_filters: self.num_filters,
+            activation: self.activation,
+            kernels: self.kernels.clone(),
+            biases: self.biases.clone(),
+            kernel_changes: Array4::<f32>::zeros(self.kernels.dim()),
+            bias_changes: Array1::<f32>::zeros(self.num_filters),
+            optimizer: Optimizer4D::default(),
+            bias_optimizer: Optimizer1D::new(self.bias_optimizer.alg, self.num_filters),
+            regularization: self.regularization,
+        }
+    }
+
+    /// Brings a replica up to date with `layer`, copying the parameters into the buffers it
+    /// already holds
+    pub fn load_parameters(&mut self, layer: &ConvLayer) {
+        self.input_size = layer.input_size;
+        self.kernel_size = layer.kernel_size;
+        self.output_size = layer.output_size;
+        self.stride = layer.stride;
+        self.padding = layer.padding;
+        self.num_filters = layer.num_filters;
+        self.activation = layer.activation;
+        self.regularization = layer.regularization;
+        self.kernels.clone_from(&layer.kernels);
+        self.biases.clone_from(&layer.biases);
+    }
+
+    /// Loss added by the regularization of the kernels
+    pub fn regularization_loss(&self) -> f32 {
+        self.regularization.penalty(&self.kernels)
+    }
+
+    /// Name of the first parameter tensor holding NaN or infinite values
+    pub fn non_finite_parameters(&self) -> Option<&'static str> {
+        if !all_finite(&self.kernels) {
+            Some("kernels")
+        } else if !all_finite(&self.biases) {
+            Some("biases")
+        } else {
+            None
+        }
+    }
 
-        prev_error
+    /// Whether the output of the last forward pass holds NaN or infinite values
+    pub fn output_is_finite(&self) -> bool {
+        all_finite(&self.output)
+    }
+
+    /// Sum of the squares of the accumulated gradients
+    pub fn gradient_sum_squares(&self) -> f32 {
+        self.kernel_changes.mapv(|x| x * x).sum() + self.bias_changes.mapv(|x| x * x).sum()
+    }
+
+    /// Clips the gradients accumulated over `minibatch_size` samples
+    pub fn clip_gradients(
+        &mut self,
+        clipping: GradientClipping,
+        global_norm: f32,
+        minibatch_size: usize,
+    ) {
+        clipping.apply(&mut self.kernel_changes, global_norm, minibatch_size);
+        clipping.apply(&mut self.bias_changes, global_norm, minibatch_size);
+    }
+
+    pub fn set_learning_rate(&mut self, lr: f32) {
+        self.optimizer.alg = self.optimizer.alg.with_learning_rate(lr);
+        self.bias_optimizer.alg = self.bias_optimizer.alg.with_learning_rate(lr);
     }
 
     pub fn update(&mut self, minibatch_size: usize) {
         self.kernel_changes /= minibatch_size as f32;
-        self.kernels += &self.optimizer.weight_changes(&self.kernel_changes);
-        self.kernel_changes = Array4::<f32>::zeros((
-            self.num_filters,
-            self.kernel_size,
-            self.kernel_size,
-            self.input_size.2,
-        ));
+        self.bias_changes /= minibatch_size as f32;
+        self.regularization
+            .add_gradients(&self.kernels, &mut self.kernel_changes);
+        let decay = self
+            .regularization
+            .decay(&self.kernels, self.optimizer.alg.learning_rate());
+        self.kernels += &self
+            .optimizer
+            .weight_changes(&self.kernels, &self.kernel_changes);
+        if let Some(decay) = decay {
+            self.kernels += &decay;
+        }
+        self.biases += &self
+            .bias_optimizer
+            .weight_changes(&self.biases, &self.bias_changes);
+        self.kernel_changes = Array4::<f32>::zeros(self.kernels.dim());
+        self.bias_changes = Array1::<f32>::zeros(self.num_filters);
     }
 }
@@ -1,32 +1,42 @@
-use crate::activation::{backward, forward, Activation};
-use crate::optimizer::{Optimizer2D, OptimizerAlg};
-use crate::utils::outer;
-use ndarray::{Array1, Array2};
-use rand::Rng;
+use crate::activation::{backward_batch_error, forward_batch, Activation};
+use crate::clipping::GradientClipping;
+use crate::divergence::all_finite;
+use crate::optimizer::{Optimizer1D, Optimizer2D, OptimizerAlg};
+use crate::regularization::Regularization;
+use ndarray::{Array1, Array2, Axis};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use rand_distr::{Distribution, Normal};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Formatter};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct DenseLayer {
     input_size: usize,
     pub output_size: usize,
     #[serde(skip)]
-    input: Array1<f32>,
+    input: Array2<f32>,
     #[serde(skip)]
-    pub output: Array1<f32>,
+    pub output: Array2<f32>,
     biases: Array1<f32>,
     weights: Array2<f32>,
     #[serde(skip)]
     bias_changes: Array1<f32>,
     #[serde(skip)]
     weight_changes: Array2<f32>,
-    activation: Activation,
+    pub activation: Activation,
     pub transition_shape: (usize, usize, usize),
     optimizer: Optimizer2D,
+    #[serde(default)]
+    bias_optimizer: Optimizer1D,
     dropout: Option<f32>,
     #[serde(skip)]
-    dropout_mask: Array1<f32>,
+    dropout_mask: Array2<f32>,
+    /// Saved so a resumed run draws the same masks, models from before load with a fresh one
+    #[serde(default = "ChaCha8Rng::from_entropy")]
+    dropout_rng: ChaCha8Rng,
+    #[serde(default)]
+    pub regularization: Regularization,
 }
 
 impl Debug for DenseLayer {
@@ -37,6 +47,7 @@ impl Debug for DenseLayer {
         s.push_str(&format!("Output Size: {}\n", self.output_size));
         s.push_str(&format!("Activation: {:?}\n", self.activation));
         s.push_str(&format!("Dropout: {:?}\n", self.dropout));
+        s.push_str(&format!("Regularization: {:?}\n", self.regularization));
         s.push_str(&format!("Optimizer: {:?}\n", self.optimizer.alg));
 
         write!(f, "{}", s)
@@ -47,7 +58,12 @@ impl DenseLayer {
     pub fn zero(&mut self) {
         self.bias_changes = Array1::<f32>::zeros(self.output_size);
         self.weight_changes = Array2::<f32>::zeros((self.output_size, self.input_size));
-        self.output = Array1::<f32>::zeros(self.output_size);
+        self.output = Array2::<f32>::zeros((0, self.output_size));
+        // Models saved before biases had their own optimizer state load without one
+        if self.bias_optimizer.momentum1.len() != self.output_size {
+            self.bias_optimizer =
+                Optimizer1D::new(self.optimizer.alg.without_weight_decay(), self.output_size);
+        }
     }
 
     /// Create a new fully connected layer with the given parameters
@@ -59,23 +75,45 @@ impl DenseLayer {
         dropout: Option<f32>,
         transition_shape: (usize, usize, usize),
     ) -> DenseLayer {
-        let thread_rng = &mut rand::thread_rng();
+        DenseLayer::new_with_rng(
+            input_size,
+            output_size,
+            activation,
+            optimizer_alg,
+            dropout,
+            transition_shape,
+            &mut rand::thread_rng(),
+        )
+    }
+
+    /// Same as `new`, drawing the initial weights and the dropout seed from `rng` so
+    /// they can be reproduced
+    pub fn new_with_rng<R: Rng>(
+        input_size: usize,
+        output_size: usize,
+        activation: Activation,
+        optimizer_alg: OptimizerAlg,
+        dropout: Option<f32>,
+        transition_shape: (usize, usize, usize),
+        rng: &mut R,
+    ) -> DenseLayer {
         let normal = Normal::new(0.0, (2.0 / input_size as f32).sqrt()).unwrap();
         // Use He initialisation by using a mean of 0.0 and a standard deviation of sqrt(2/input_neurons)
         // Initialize the weights with random values drawn from the normal distribution
         let weights =
-            Array2::<f32>::from_shape_fn((output_size, input_size), |_| normal.sample(thread_rng));
+            Array2::<f32>::from_shape_fn((output_size, input_size), |_| normal.sample(rng));
 
         // Initialize the biases with a small positive value
         let biases = Array1::<f32>::from_elem(output_size, 0.01);
 
-        let optimizer = Optimizer2D::new(optimizer_alg, input_size, output_size);
+        let optimizer = Optimizer2D::new(optimizer_alg, (output_size, input_size));
+        let bias_optimizer = Optimizer1D::new(optimizer_alg.without_weight_decay(), output_size);
 
         let layer: DenseLayer = DenseLayer {
             input_size,
             output_size,
-            input: Array1::<f32>::zeros(input_size),
-            output: Array1::<f32>::zeros(output_size),
+            input: Array2::<f32>::zeros((0, input_size)),
+            output: Array2::<f32>::zeros((0, output_size)),
             biases,
             weights,
             bias_changes: Array1::<f32>::zeros(output_size),
@@ -83,53 +121,165 @@ impl DenseLayer {
             activation,
             transition_shape,
             optimizer,
+            bias_optimizer,
             dropout,
-            dropout_mask: Array1::<f32>::zeros(output_size),
+            dropout_mask: Array2::<f32>::zeros((0, output_size)),
+            dropout_rng: ChaCha8Rng::seed_from_u64(rng.gen()),
+            regularization: Regularization::none(),
         };
 
         layer
     }
 
-    pub fn forward_propagate(&mut self, input: Array1<f32>, training: bool) -> Array1<f32> {
-        if training && self.dropout.is_some() {
-            let dropout = self.dropout.unwrap();
-            let mut rng = rand::thread_rng();
-            self.dropout_mask =
-                Array1::<f32>::from_shape_fn((self.output_size,), |_| rng.gen::<f32>());
-            self.dropout_mask = self
-                .dropout_mask
-                .mapv(|x| if x < dropout { 0.0 } else { 1.0 });
-            let logits: Array1<f32> = self.weights.dot(&input) + &self.biases;
-            self.output = forward(logits, self.activation);
+    /// Forward a batch of flattened inputs, one sample per row
+    pub fn forward_propagate(&mut self, input: Array2<f32>, training: bool) -> Array2<f32> {
+        self.output = self.infer(&input);
+        if let (true, Some(dropout)) = (training, self.dropout) {
+            let rng = &mut self.dropout_rng;
+            self.dropout_mask = Array2::<f32>::from_shape_fn(self.output.dim(), |_| {
+                if rng.gen::<f32>() < dropout {
+                    0.0
+                } else {
+                    1.0
+                }
+            });
             self.output *= &self.dropout_mask;
-            self.input = input;
-            self.output.clone()
-        } else {
-            let logits: Array1<f32> = self.weights.dot(&input) + &self.biases;
-            self.output = forward(logits, self.activation);
-            self.input = input;
-            self.output.clone()
         }
+        self.input = input;
+        self.output.clone()
     }
 
-    pub fn back_propagate(&mut self, error: Array1<f32>, training: bool) -> Array1<f32> {
+    /// Forward without dropout or caching, so it can run from several threads at once
+    pub fn infer(&self, input: &Array2<f32>) -> Array2<f32> {
+        let logits: Array2<f32> = input.dot(&self.weights.t()) + &self.biases;
+        forward_batch(logits, self.activation)
+    }
+
+    pub fn back_propagate(&mut self, error: Array2<f32>, training: bool) -> Array2<f32> {
+        let error = backward_batch_error(&self.output, error, self.activation);
+        self.back_propagate_logits(error, training)
+    }
+
+    /// Back propagation starting from the error on the logits, for losses that already
+    /// account for this layer's activation
+    pub fn back_propagate_logits(&mut self, error: Array2<f32>, training: bool) -> Array2<f32> {
         let mut error = error;
         if self.dropout.is_some() && training {
             error *= &self.dropout_mask;
         }
-        error *= &backward(self.output.clone(), self.activation);
-        let prev_error = self.weights.t().dot(&error);
-        self.weight_changes -= &(outer(error.clone(), self.input.clone()));
-        self.bias_changes -= &error;
+        let prev_error = error.dot(&self.weights);
+        self.weight_changes -= &error.t().dot(&self.input);
+        self.bias_changes -= &error.sum_axis(Axis(0));
 
         prev_error
     }
 
+    /// Restarts the stream of dropout masks from `seed`
+    pub fn seed_dropout(&mut self, seed: u64) {
+        self.dropout_rng = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    /// Adds the gradients accumulated by another copy of this layer, e.g. a worker replica
+    pub fn accumulate_gradients(&mut self, other: &DenseLayer) {
+        self.weight_changes += &other.weight_changes;
+        self.bias_changes += &other.bias_changes;
+    }
+
+    /// A copy for a worker thread to run part of a minibatch on. It leaves out the optimizer
+    /// state, which only the original layer needs for its update.
+    pub fn replica(&self) -> DenseLayer {
+        DenseLayer {
+            input_size: self.input_size,
+            output_size: self.output_size,
+            input: Array2::<f32>::zeros((0, self.input_size)),
+            output: Array2::<f32>::zeros((0, self.output_size)),
+            biases: self.biases.clone(),
+            weights: self.weights.clone(),
+            bias_changes: Array1::<f32>::zeros(self.output_size),
+            weight_changes: Array2::<f32>::zeros(self.weights.dim()),
+            activation: self.activation,
+            transition_shape: self.transition_shape,
+            optimizer: Optimizer2D::default(),
+            bias_optimizer: Optimizer1D::new(self.bias_optimizer.alg, self.output_size),
+            dropout: self.dropout,
+            dropout_mask: Array2::<f32>::zeros((0, self.output_size)),
+            dropout_rng: self.dropout_rng.clone(),
+            regularization: self.regularization,
+        }
+    }
+
+    /// Brings a replica up to date with `layer`, copying the parameters into the buffers it
+    /// already holds
+    pub fn load_parameters(&mut self, layer: &DenseLayer) {
+        self.input_size = layer.input_size;
+        self.output_size = layer.output_size;
+        self.activation = layer.activation;
+        self.transition_shape = layer.transition_shape;
+        self.dropout = layer.dropout;
+        self.regularization = layer.regularization;
+        self.biases.clone_from(&layer.biases);
+        self.weights.clone_from(&layer.weights);
+    }
+
+    /// Loss added by the regularization of the weights
+    pub fn regularization_loss(&self) -> f32 {
+        self.regularization.penalty(&self.weights)
+    }
+
+    /// Name of the first parameter tensor holding NaN or infinite values
+    pub fn non_finite_parameters(&self) -> Option<&'static str> {
+        if !all_finite(&self.weights) {
+            Some("weights")
+        } else if !all_finite(&self.biases) {
+            Some("biases")
+        } else {
+            None
+        }
+    }
+
+    /// Whether the output of the last forward pass holds NaN or infinite values
+    pub fn output_is_finite(&self) -> bool {
+        all_finite(&self.output)
+    }
+
+    /// Sum of the squares of the accumulated gradients
+    pub fn gradient_sum_squares(&self) -> f32 {
+        self.weight_changes.mapv(|x| x * x).sum() + self.bias_changes.mapv(|x| x * x).sum()
+    }
+
+    /// Clips the gradients accumulated over `minibatch_size` samples
+    pub fn clip_gradients(
+        &mut self,
+        clipping: GradientClipping,
+        global_norm: f32,
+        minibatch_size: usize,
+    ) {
+        clipping.apply(&mut self.weight_changes, global_norm, minibatch_size);
+        clipping.apply(&mut self.bias_changes, global_norm, minibatch_size);
+    }
+
+    pub fn set_learning_rate(&mut self, lr: f32) {
+        self.optimizer.alg = self.optimizer.alg.with_learning_rate(lr);
+        self.bias_optimizer.alg = self.bias_optimizer.alg.with_learning_rate(lr);
+    }
+
     pub fn update(&mut self, minibatch_size: usize) {
         self.weight_changes /= minibatch_size as f32;
         self.bias_changes /= minibatch_size as f32;
-        self.weights += &self.optimizer.weight_changes(&self.weight_changes);
-        self.biases += &self.optimizer.bias_changes(&self.bias_changes);
+        self.regularization
+            .add_gradients(&self.weights, &mut self.weight_changes);
+        let decay = self
+            .regularization
+            .decay(&self.weights, self.optimizer.alg.learning_rate());
+        self.weights += &self
+            .optimizer
+            .weight_changes(&self.weights, &self.weight_changes);
+        if let Some(decay) = decay {
+            self.weights += &decay;
+        }
+        self.biases += &self
+            .bias_optimizer
+            .weight_changes(&self.biases, &self.bias_changes);
         self.weight_changes = Array2::<f32>::zeros((self.output_size, self.input_size));
         self.bias_changes = Array1::<f32>::zeros(self.output_size);
     }
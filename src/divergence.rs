@@ -0,0 +1,28 @@
+use ndarray::{Array, Dimension};
+use serde::{Deserialize, Serialize};
+
+/// What `CNN::train` does once a layer's weights or activations hold NaN or infinite values.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum DivergenceAction {
+    /// Stop training with `Error::NonFinite`
+    #[default]
+    Stop,
+    /// Restore the layers and learning-rate schedule saved at the end of the last good
+    /// epoch and run the epoch again on a fresh shuffle. Training stops with
+    /// `Error::NonFinite` after this many rollbacks.
+    Rollback(usize),
+}
+
+/// Checks run during training against diverging weights.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct DivergenceGuard {
+    pub action: DivergenceAction,
+    /// Also check the outputs of the convolutional and dense layers after every forward
+    /// pass, which finds the first layer to go bad rather than the weights it poisoned
+    pub check_activations: bool,
+}
+
+/// Whether every value of `array` is finite
+pub fn all_finite<D: Dimension>(array: &Array<f32, D>) -> bool {
+    array.iter().all(|x| x.is_finite())
+}
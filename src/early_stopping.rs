@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+/// Per-epoch metric watched by `EarlyStopping`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Metric {
+    /// Needs a validation set in the training data
+    #[default]
+    ValidationAccuracy,
+    /// Needs a validation set in the training data
+    ValidationLoss,
+    /// Selects on the test set, prefer the validation metrics to keep the test set unseen
+    TestAccuracy,
+    TestLoss,
+    TrainingAccuracy,
+    TrainingLoss,
+}
+
+/// Results of one training epoch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EpochMetrics {
+    pub train_acc: f32,
+    pub train_loss: f32,
+    /// None without a validation set
+    pub validation: Option<(f32, f32)>,
+    /// None without a test set
+    pub test: Option<(f32, f32)>,
+}
+
+impl Metric {
+    /// Picks this metric out of an epoch's results, None for a validation or test metric
+    /// when there is no such set
+    pub fn value(&self, metrics: &EpochMetrics) -> Option<f32> {
+        match self {
+            Metric::ValidationAccuracy => metrics.validation.map(|(acc, _)| acc),
+            Metric::ValidationLoss => metrics.validation.map(|(_, loss)| loss),
+            Metric::TestAccuracy => metrics.test.map(|(acc, _)| acc),
+            Metric::TestLoss => metrics.test.map(|(_, loss)| loss),
+            Metric::TrainingAccuracy => Some(metrics.train_acc),
+            Metric::TrainingLoss => Some(metrics.train_loss),
+        }
+    }
+
+    pub fn needs_validation(&self) -> bool {
+        matches!(self, Metric::ValidationAccuracy | Metric::ValidationLoss)
+    }
+
+    pub fn needs_test(&self) -> bool {
+        matches!(self, Metric::TestAccuracy | Metric::TestLoss)
+    }
+
+    pub fn higher_is_better(&self) -> bool {
+        matches!(
+            self,
+            Metric::ValidationAccuracy | Metric::TestAccuracy | Metric::TrainingAccuracy
+        )
+    }
+}
+
+/// Ends training once `metric` has not improved by more than `min_delta` for `patience`
+/// epochs in a row.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct EarlyStopping {
+    pub metric: Metric,
+    pub patience: usize,
+    pub min_delta: f32,
+    /// Put back the layers from the best epoch when training ends
+    pub restore_best: bool,
+}
+
+impl Default for EarlyStopping {
+    fn default() -> Self {
+        EarlyStopping {
+            metric: Metric::ValidationAccuracy,
+            patience: 5,
+            min_delta: 0.0,
+            restore_best: true,
+        }
+    }
+}
+
+/// Progress of a training run against an `EarlyStopping` policy
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EarlyStopper {
+    pub policy: EarlyStopping,
+    /// Best value of the metric so far
+    pub best: Option<f32>,
+    /// Epoch, counted from 0 over the run, that scored `best`
+    pub best_epoch: usize,
+    /// Epochs since `best` last improved
+    pub bad_epochs: usize,
+}
+
+impl EarlyStopper {
+    pub fn new(policy: EarlyStopping) -> EarlyStopper {
+        EarlyStopper {
+            policy,
+            best: None,
+            best_epoch: 0,
+            bad_epochs: 0,
+        }
+    }
+
+    /// Records the metric of `epoch` and returns whether it is the new best
+    pub fn record(&mut self, epoch: usize, value: f32) -> bool {
+        let improved = match self.best {
+            None => true,
+            Some(best) if self.policy.metric.higher_is_better() => {
+                value > best + self.policy.min_delta
+            }
+            Some(best) => value < best - self.policy.min_delta,
+        };
+        if improved {
+            self.best = Some(value);
+            self.best_epoch = epoch;
+            self.bad_epochs = 0;
+        } else {
+            self.bad_epochs += 1;
+        }
+        improved
+    }
+
+    /// Whether the metric has stalled for long enough to stop
+    pub fn should_stop(&self) -> bool {
+        self.bad_epochs >= self.policy.patience.max(1)
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt::{Display, Formatter};
+
+/// Errors returned by the public API instead of panicking.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a model or dataset file failed
+    Io(std::io::Error),
+    /// A JSON model could not be (de)serialized
+    Json(serde_json::Error),
+    /// A binary model could not be (de)serialized
+    Bincode(bincode::Error),
+    /// A dataset is missing files or malformed
+    Dataset(String),
+    /// A layer was added before `CNN::set_input_shape`
+    InputShapeNotSet,
+    /// The input shape is empty, has more than three dimensions or a zero-sized one
+    InvalidInputShape(Vec<usize>),
+    /// A layer cannot be built or placed where it was asked for
+    InvalidLayer(String),
+    /// Training diverged, leaving NaN or infinite values in a tensor of the layer at
+    /// index `layer`
+    NonFinite {
+        layer: usize,
+        kind: String,
+        tensor: &'static str,
+    },
+}
+
+/// Shorthand for results carrying the crate's `Error`.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Json(e) => write!(f, "JSON error: {}", e),
+            Error::Bincode(e) => write!(f, "Binary format error: {}", e),
+            Error::Dataset(msg) => write!(f, "Dataset error: {}", msg),
+            Error::InputShapeNotSet => {
+                write!(f, "Input shape not set, use cnn.set_input_shape()")
+            }
+            Error::InvalidInputShape(shape) => write!(f, "Invalid input shape: {:?}", shape),
+            Error::InvalidLayer(msg) => write!(f, "{}", msg),
+            Error::NonFinite {
+                layer,
+                kind,
+                tensor,
+            } => write!(
+                f,
+                "Non-finite values in the {} of layer {} ({})",
+                tensor, layer, kind
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Bincode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Bincode(e)
+    }
+}
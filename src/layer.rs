@@ -1,13 +1,18 @@
+use crate::avpl::{AvplLayer, GlobalAvplLayer};
 use crate::conv_layers::ConvLayer;
 use crate::dense_layer::*;
 use crate::mxpl::*;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Formatter};
 
-#[derive(Serialize, Deserialize)]
+// A model only holds a handful of layers, so the padding from the large ConvLayer is negligible
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum Layer {
     Conv(ConvLayer),
     Mxpl(MxplLayer),
+    Avpl(AvplLayer),
+    GlobalAvpl(GlobalAvplLayer),
     Dense(DenseLayer),
 }
 
@@ -16,7 +21,30 @@ impl Debug for Layer {
         match self {
             Layer::Conv(layer) => write!(f, "{:?}", layer),
             Layer::Mxpl(layer) => write!(f, "{:?}", layer),
+            Layer::Avpl(layer) => write!(f, "{:?}", layer),
+            Layer::GlobalAvpl(layer) => write!(f, "{:?}", layer),
             Layer::Dense(layer) => write!(f, "{:?}", layer),
         }
     }
 }
+
+impl Layer {
+    /// A copy of this layer for a worker thread, without any optimizer state
+    pub fn replica(&self) -> Layer {
+        match self {
+            Layer::Conv(layer) => Layer::Conv(layer.replica()),
+            Layer::Dense(layer) => Layer::Dense(layer.replica()),
+            layer => layer.clone(),
+        }
+    }
+
+    /// Brings a replica up to date with `layer` before a minibatch. Layers with parameters
+    /// reuse their buffers, the others, or a replica of another kind, are replaced.
+    pub fn load_parameters(&mut self, layer: &Layer) {
+        match (self, layer) {
+            (Layer::Conv(replica), Layer::Conv(layer)) => replica.load_parameters(layer),
+            (Layer::Dense(replica), Layer::Dense(layer)) => replica.load_parameters(layer),
+            (replica, layer) => *replica = layer.replica(),
+        }
+    }
+}
@@ -1,9 +1,19 @@
 pub mod activation;
+pub mod avpl;
+pub mod checkpoint;
+pub mod clipping;
 pub mod cnn;
 pub mod conv_layers;
 pub mod dense_layer;
+pub mod divergence;
+pub mod early_stopping;
+pub mod error;
 pub mod layer;
+pub mod loss;
 pub mod mnist_impl;
 pub mod mxpl;
 pub mod optimizer;
+pub mod output;
+pub mod regularization;
+pub mod scheduler;
 pub mod utils;
@@ -0,0 +1,93 @@
+use crate::activation::{backward_batch_error, Activation};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+/// Keeps logarithms and divisions away from zero
+const EPSILON: f32 = 1e-7;
+
+/// Loss minimised during training, computed per sample and averaged over the batch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Loss {
+    /// Categorical cross-entropy against the one-hot labels
+    #[default]
+    CrossEntropy,
+    /// Mean of the squared differences to the one-hot labels
+    MeanSquaredError,
+    /// Cross-entropy of every output as an independent yes/no probability
+    BinaryCrossEntropy,
+    /// Cross-entropy against one-hot labels smoothed by the given amount, spread over all classes
+    LabelSmoothing(f32),
+    /// Cross-entropy scaled by (1 - p)^gamma, focusing on badly classified samples
+    Focal(f32),
+}
+
+impl Loss {
+    /// Desired outputs for a batch of labels
+    pub fn targets(&self, labels: &[usize], classes: usize) -> Array2<f32> {
+        let one_hot = Array2::<f32>::from_shape_fn((labels.len(), classes), |(n, i)| {
+            (labels[n] == i) as usize as f32
+        });
+        match self {
+            Loss::LabelSmoothing(smoothing) => {
+                one_hot * (1.0 - smoothing) + smoothing / classes as f32
+            }
+            _ => one_hot,
+        }
+    }
+
+    /// Mean loss of a batch of outputs, one sample per row
+    pub fn loss(&self, output: &Array2<f32>, labels: &[usize]) -> f32 {
+        let classes = output.ncols() as f32;
+        let targets = self.targets(labels, output.ncols());
+        let mut total = 0.0;
+        for (&y, &t) in output.iter().zip(targets.iter()) {
+            let p = y.clamp(EPSILON, 1.0 - EPSILON);
+            total += match self {
+                Loss::CrossEntropy | Loss::LabelSmoothing(_) => -t * p.ln(),
+                Loss::MeanSquaredError => (y - t).powi(2) / classes,
+                Loss::BinaryCrossEntropy => -(t * p.ln() + (1.0 - t) * (1.0 - p).ln()),
+                Loss::Focal(gamma) => -t * (1.0 - p).powf(*gamma) * p.ln(),
+            };
+        }
+
+        total / labels.len() as f32
+    }
+
+    /// Gradient of each sample's loss with respect to the outputs
+    pub fn gradient(&self, output: &Array2<f32>, labels: &[usize]) -> Array2<f32> {
+        let classes = output.ncols() as f32;
+        let targets = self.targets(labels, output.ncols());
+        let mut gradient = Array2::<f32>::zeros(output.dim());
+        for ((g, &y), &t) in gradient.iter_mut().zip(output.iter()).zip(targets.iter()) {
+            let p = y.clamp(EPSILON, 1.0 - EPSILON);
+            *g = match self {
+                Loss::CrossEntropy | Loss::LabelSmoothing(_) => -t / p,
+                Loss::MeanSquaredError => 2.0 * (y - t) / classes,
+                Loss::BinaryCrossEntropy => (p - t) / (p * (1.0 - p)),
+                Loss::Focal(gamma) => {
+                    t * (gamma * (1.0 - p).powf(gamma - 1.0) * p.ln() - (1.0 - p).powf(*gamma) / p)
+                }
+            };
+        }
+
+        gradient
+    }
+
+    /// Gradient of each sample's loss with respect to the logits of a final layer using
+    /// `activation`. Cross-entropy after softmax and binary cross-entropy after sigmoid
+    /// simplify to `output - targets`, which stays accurate when the outputs saturate.
+    pub fn output_error(
+        &self,
+        output: &Array2<f32>,
+        labels: &[usize],
+        activation: Activation,
+    ) -> Array2<f32> {
+        match (self, activation) {
+            (Loss::CrossEntropy | Loss::LabelSmoothing(_), Activation::Softmax)
+            | (Loss::BinaryCrossEntropy, Activation::Sigmoid) => {
+                output - &self.targets(labels, output.ncols())
+            }
+            _ => backward_batch_error(output, self.gradient(output, labels), activation),
+        }
+    }
+}
@@ -1,11 +1,13 @@
 use conv_nn::activation::Activation;
 use conv_nn::cnn::*;
+use conv_nn::conv_layers::Padding;
+use conv_nn::error::Error;
 use conv_nn::mnist_impl::*;
 use conv_nn::optimizer::*;
 
-fn main() {
+fn main() -> Result<(), Error> {
     // Load MNIST dataset
-    let data = load_mnist("./data/");
+    let data = load_mnist("./data/")?;
 
     // Set hyperparameters
     let hyperparameters = Hyperparameters {
@@ -17,16 +19,18 @@ fn main() {
 
     // Create CNN architecture
     let mut cnn = CNN::new(data, hyperparameters);
-    cnn.set_input_shape(vec![28, 28, 3]);
-    cnn.add_conv_layer(8, 3);
-    cnn.add_mxpl_layer(2);
-    cnn.add_dense_layer(128, Activation::Relu, Some(0.25));
-    cnn.add_dense_layer(64, Activation::Relu, Some(0.25));
-    cnn.add_dense_layer(10, Activation::Softmax, None);
+    cnn.set_input_shape(vec![28, 28, 3])?;
+    cnn.add_conv_layer(8, (3, 3), (1, 1), Padding::Valid, Activation::Relu)?;
+    cnn.add_mxpl_layer((2, 2), (2, 2))?;
+    cnn.add_dense_layer(128, Activation::Relu, Some(0.25))?;
+    cnn.add_dense_layer(64, Activation::Relu, Some(0.25))?;
+    cnn.add_dense_layer(10, Activation::Softmax, None)?;
 
     // Train the CNN
-    cnn.train();
+    cnn.train()?;
 
     // Save the CNN
-    cnn.save(true);
+    cnn.save(true)?;
+
+    Ok(())
 }
@@ -1,29 +1,54 @@
-use crate::utils::{load_image, TrainImage, TrainingData};
-use ndarray::Array3;
-use rand::seq::IteratorRandom;
+use crate::error::{Error, Result};
+use crate::utils::{load_image, TrainImage, TrainingData, ValidationSplit};
+use ndarray::{stack, Array3, Array4, Axis};
+use rand::seq::SliceRandom;
+use rand::Rng;
 use rust_mnist::Mnist;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
 
+/// Files `load_mnist` expects in the dataset directory, with the IDX magic number and
+/// dimensions rust_mnist insists on
+const MNIST_FILES: [(&str, u32, &[usize]); 4] = [
+    ("train-images-idx3-ubyte", 2051, &[60000, 28, 28]),
+    ("train-labels-idx1-ubyte", 2049, &[60000]),
+    ("t10k-images-idx3-ubyte", 2051, &[10000, 28, 28]),
+    ("t10k-labels-idx1-ubyte", 2049, &[10000]),
+];
+
 /// Loads the MNIST dataset and returns a structured `TrainingData` object.
-pub fn load_mnist<T>(mnist_path: T) -> TrainingData
+pub fn load_mnist<T>(mnist_path: T) -> Result<TrainingData>
 where
     T: AsRef<Path>,
 {
     let (rows, cols) = (28, 28);
     let mnist_path = mnist_path.as_ref();
-    let mnist = Mnist::new(mnist_path.to_str().unwrap());
+    for (file_name, magic, dims) in MNIST_FILES {
+        let file_path = mnist_path.join(file_name);
+        if !file_path.is_file() {
+            return Err(Error::Dataset(format!(
+                "Missing MNIST file {}",
+                file_path.display()
+            )));
+        }
+        check_idx_file(&file_path, magic, dims)?;
+    }
+    // rust_mnist panics on files it cannot parse, which the checks above rule out. It
+    // also expects the directory to end with a separator.
+    let mnist = Mnist::new(&format!("{}/", mnist_path.display()));
 
     // Ensure "unpacked" directory exists
     let unpacked_path = mnist_path.join("unpacked");
     if !unpacked_path.exists() {
-        std::fs::create_dir(&unpacked_path).expect("Failed to create unpacked folder.");
+        std::fs::create_dir(&unpacked_path)?;
     }
 
     // Helper function to process images
     fn process_images(
-        data: &Vec<[u8; 784]>,
-        labels: &Vec<u8>,
+        data: &[[u8; 784]],
+        labels: &[u8],
         rows: usize,
         cols: usize,
     ) -> (Vec<TrainImage>, Vec<usize>) {
@@ -33,7 +58,7 @@ where
                 let mut img = Array3::<f32>::zeros((rows, cols, 1));
                 for j in 0..rows {
                     for k in 0..cols {
-                        img[[j, k, 0]] = img_data[(j * cols + k) as usize] as f32 / 255.0;
+                        img[[j, k, 0]] = img_data[j * cols + k] as f32 / 255.0;
                     }
                 }
                 TrainImage::Image(img)
@@ -51,7 +76,7 @@ where
     // Classes map
     let classes: HashMap<usize, usize> = (0..10).enumerate().collect();
 
-    TrainingData {
+    Ok(TrainingData {
         trn_img,
         trn_lbl,
         tst_img,
@@ -61,28 +86,165 @@ where
         trn_size: 60000,
         tst_size: 10000,
         classes,
+        ..TrainingData::default()
+    })
+}
+
+/// Checks that the IDX file at `path` has the expected header and exactly the data it
+/// describes
+fn check_idx_file(path: &Path, magic: u32, dims: &[usize]) -> Result<()> {
+    let malformed = |reason: String| {
+        Err(Error::Dataset(format!(
+            "Malformed MNIST file {}: {}",
+            path.display(),
+            reason
+        )))
+    };
+    let mut file = File::open(path)?;
+    let mut header = vec![0u8; 4 * (dims.len() + 1)];
+    if file.read_exact(&mut header).is_err() {
+        return malformed(String::from("too short for an IDX header"));
+    }
+    let words: Vec<u32> = header
+        .chunks(4)
+        .map(|word| u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    if words[0] != magic {
+        return malformed(format!(
+            "expected magic number {}, found {}",
+            magic, words[0]
+        ));
+    }
+    let found: Vec<usize> = words[1..].iter().map(|&dim| dim as usize).collect();
+    if found != dims {
+        return malformed(format!("expected dimensions {:?}, found {:?}", dims, found));
     }
+    let expected_len = (header.len() + dims.iter().product::<usize>()) as u64;
+    let len = file.metadata()?.len();
+    if len != expected_len {
+        return malformed(format!("expected {} bytes, found {}", expected_len, len));
+    }
+
+    Ok(())
 }
 
-/// Retrieves a random training image and its label from the `TrainingData`.
-pub fn get_random_image(data: &TrainingData) -> (Array3<f32>, usize) {
-    get_random_sample(&data.trn_img, &data.trn_lbl)
+/// Shuffles the indices of `size` samples and splits them into minibatches of `batch_size`,
+/// so one epoch visits every sample exactly once. The last batch holds whatever is left over.
+pub fn epoch_batches<R: Rng>(size: usize, batch_size: usize, rng: &mut R) -> Vec<Vec<usize>> {
+    let mut indices: Vec<usize> = (0..size).collect();
+    indices.shuffle(rng);
+    indices
+        .chunks(batch_size)
+        .map(|chunk| chunk.to_vec())
+        .collect()
 }
 
-/// Retrieves a random test image and its label from the `TrainingData`.
-pub fn get_random_test_image(data: &TrainingData) -> (Array3<f32>, usize) {
-    get_random_sample(&data.tst_img, &data.tst_lbl)
+/// Splits the indices of `size` samples into minibatches of `batch_size`, in order.
+pub fn ordered_batches(size: usize, batch_size: usize) -> Vec<Vec<usize>> {
+    let indices: Vec<usize> = (0..size).collect();
+    indices
+        .chunks(batch_size)
+        .map(|chunk| chunk.to_vec())
+        .collect()
 }
 
-/// Helper function to fetch a random image and label from the provided dataset.
-fn get_random_sample(images: &[TrainImage], labels: &[usize]) -> (Array3<f32>, usize) {
-    let mut rng = rand::thread_rng();
-    let (img, label) = images.iter().zip(labels.iter()).choose(&mut rng).unwrap();
-    match img {
-        TrainImage::Image(img) => (img.clone(), *label),
-        TrainImage::Path(img_path) => {
-            let img = load_image(img_path).expect("Failed to load image from path");
-            (img, *label)
+/// Retrieves the training images at `indices` stacked as (batch, x, y, channels) and their labels.
+pub fn get_batch(data: &TrainingData, indices: &[usize]) -> Result<(Array4<f32>, Vec<usize>)> {
+    get_samples(&data.trn_img, &data.trn_lbl, indices)
+}
+
+/// Retrieves the validation images at `indices` stacked as (batch, x, y, channels) and their labels.
+pub fn get_validation_batch(
+    data: &TrainingData,
+    indices: &[usize],
+) -> Result<(Array4<f32>, Vec<usize>)> {
+    get_samples(&data.val_img, &data.val_lbl, indices)
+}
+
+/// Moves a random `split.fraction` of the training samples, appended to any validation
+/// samples already present, into the validation set. A stratified split takes the same
+/// fraction of every label.
+pub fn split_validation<R: Rng>(data: &mut TrainingData, split: ValidationSplit, rng: &mut R) {
+    let fraction = split.fraction.clamp(0.0, 1.0);
+    let mut groups: Vec<Vec<usize>> = if split.stratified {
+        let mut by_label: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, label) in data.trn_lbl.iter().enumerate() {
+            by_label.entry(*label).or_default().push(i);
         }
+        by_label.into_values().collect()
+    } else {
+        vec![(0..data.trn_lbl.len()).collect()]
+    };
+
+    // Sized by the labels rather than `trn_size`, which may not have been kept in step
+    let mut held_out = vec![false; data.trn_lbl.len()];
+    for group in &mut groups {
+        group.shuffle(rng);
+        let count = (group.len() as f32 * fraction).round() as usize;
+        for &i in &group[..count] {
+            held_out[i] = true;
+        }
+    }
+
+    let images = std::mem::take(&mut data.trn_img);
+    let labels = std::mem::take(&mut data.trn_lbl);
+    for ((image, label), held_out) in images.into_iter().zip(labels).zip(held_out) {
+        if held_out {
+            data.val_img.push(image);
+            data.val_lbl.push(label);
+        } else {
+            data.trn_img.push(image);
+            data.trn_lbl.push(label);
+        }
+    }
+    data.trn_size = data.trn_lbl.len();
+    data.val_size = data.val_lbl.len();
+}
+
+/// Retrieves the test images at `indices` stacked as (batch, x, y, channels) and their labels.
+pub fn get_test_batch(data: &TrainingData, indices: &[usize]) -> Result<(Array4<f32>, Vec<usize>)> {
+    get_samples(&data.tst_img, &data.tst_lbl, indices)
+}
+
+/// Helper function to fetch and stack the images at `indices` with their labels.
+fn get_samples(
+    images: &[TrainImage],
+    labels: &[usize],
+    indices: &[usize],
+) -> Result<(Array4<f32>, Vec<usize>)> {
+    let out_of_range = |i: usize| {
+        Error::Dataset(format!(
+            "Sample {} is out of range for a set of {} images and {} labels",
+            i,
+            images.len(),
+            labels.len()
+        ))
+    };
+    let batch_images = indices
+        .iter()
+        .map(|&i| get_sample(images.get(i).ok_or_else(|| out_of_range(i))?))
+        .collect::<Result<Vec<Array3<f32>>>>()?;
+    let labels = indices
+        .iter()
+        .map(|&i| labels.get(i).copied().ok_or_else(|| out_of_range(i)))
+        .collect::<Result<Vec<usize>>>()?;
+    let views: Vec<_> = batch_images.iter().map(|img| img.view()).collect();
+    let images = stack(Axis(0), &views)
+        .map_err(|error| Error::Dataset(format!("Images in a batch differ in shape: {}", error)))?;
+
+    Ok((images, labels))
+}
+
+/// Helper function to load an image, from memory or disk.
+fn get_sample(img: &TrainImage) -> Result<Array3<f32>> {
+    match img {
+        TrainImage::Image(img) => Ok(img.clone()),
+        TrainImage::Path(img_path) => load_image(img_path).map_err(|error| {
+            Error::Dataset(format!(
+                "Failed to load image {}: {}",
+                img_path.display(),
+                error
+            ))
+        }),
     }
 }
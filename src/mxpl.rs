@@ -1,16 +1,19 @@
-use ndarray::{Array3, Array4};
+use crate::utils::deserialize_pair;
+use ndarray::{Array4, Array5};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Formatter};
 
 /// Defines a `MaxPoolingLayer` structure.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct MxplLayer {
     input_size: (usize, usize, usize),
-    kernel_size: usize,
+    #[serde(deserialize_with = "deserialize_pair")]
+    kernel_size: (usize, usize),
     pub output_size: (usize, usize, usize),
     #[serde(skip)]
-    highest_indices: Array4<usize>,
-    stride: usize,
+    highest_indices: Array5<usize>,
+    #[serde(deserialize_with = "deserialize_pair")]
+    stride: (usize, usize),
 }
 
 impl Debug for MxplLayer {
@@ -23,13 +26,13 @@ impl Debug for MxplLayer {
         ));
         s.push_str(&format!(
             "Kernel Size: {}x{}\n",
-            self.kernel_size, self.kernel_size
+            self.kernel_size.0, self.kernel_size.1
         ));
         s.push_str(&format!(
             "Output Size: {}x{}x{}\n",
             self.output_size.0, self.output_size.1, self.output_size.2
         ));
-        s.push_str(&format!("Stride: {}\n", self.stride));
+        s.push_str(&format!("Stride: {}x{}\n", self.stride.0, self.stride.1));
 
         write!(f, "{}", s)
     }
@@ -37,63 +40,109 @@ impl Debug for MxplLayer {
 
 impl MxplLayer {
     /// Create a new max pooling layer with the given parameters
-    pub fn new(input_size: (usize, usize, usize), kernel_size: usize, stride: usize) -> MxplLayer {
-        let output_width: usize = ((input_size.0 - kernel_size) / stride) + 1;
-        let output_size = (output_width, output_width, input_size.2);
+    pub fn new(
+        input_size: (usize, usize, usize),
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+    ) -> MxplLayer {
+        let output_size = (
+            ((input_size.0 - kernel_size.0) / stride.0) + 1,
+            ((input_size.1 - kernel_size.1) / stride.1) + 1,
+            input_size.2,
+        );
         let layer: MxplLayer = MxplLayer {
             input_size,
             kernel_size,
             output_size,
             stride,
-            highest_indices: Array4::<usize>::zeros((output_width, output_width, input_size.2, 2)),
+            highest_indices: Array5::<usize>::zeros((
+                0,
+                output_size.0,
+                output_size.1,
+                input_size.2,
+                2,
+            )),
         };
 
         layer
     }
 
     pub fn zero(&mut self) {
-        self.highest_indices =
-            Array4::<usize>::zeros((self.output_size.0, self.output_size.1, self.input_size.2, 2));
+        self.highest_indices = Array5::<usize>::zeros((
+            0,
+            self.output_size.0,
+            self.output_size.1,
+            self.input_size.2,
+            2,
+        ));
+    }
+
+    /// Forward a batch of inputs shaped (batch, x, y, channels)
+    pub fn forward_propagate(&mut self, input: Array4<f32>) -> Array4<f32> {
+        let (output, highest_indices) = self.pool(input);
+        self.highest_indices = highest_indices;
+
+        output
+    }
+
+    /// Same as `forward_propagate` but leaves the layer untouched, so it can run
+    /// from several threads at once
+    pub fn infer(&self, input: Array4<f32>) -> Array4<f32> {
+        self.pool(input).0
     }
 
-    pub fn forward_propagate(&mut self, input: Array3<f32>) -> Array3<f32> {
-        let mut output: Array3<f32> = Array3::<f32>::zeros(self.output_size);
-
-        for f in 0..self.output_size.2 {
-            for y in 0..self.output_size.1 {
-                for x in 0..self.output_size.0 {
-                    output[[x, y, f]] = -1.0;
-                    self.highest_indices[[x, y, f, 0]] = 0;
-                    self.highest_indices[[x, y, f, 1]] = 0;
-
-                    for ky in 0..self.kernel_size {
-                        for kx in 0..self.kernel_size {
-                            let index: (usize, usize) =
-                                (x * self.stride + kx, y * self.stride + ky);
-                            let value: f32 = input[[index.0, index.1, f]];
-
-                            if value > output[[x, y, f]] {
-                                output[[x, y, f]] = value;
-                                self.highest_indices[[x, y, f, 0]] = index.0;
-                                self.highest_indices[[x, y, f, 1]] = index.1;
+    /// Maximum of every window along with the input position it was found at
+    fn pool(&self, input: Array4<f32>) -> (Array4<f32>, Array5<usize>) {
+        let batch_size = input.dim().0;
+        let (ox, oy, oc) = self.output_size;
+        let mut output: Array4<f32> = Array4::<f32>::zeros((batch_size, ox, oy, oc));
+        let mut highest_indices = Array5::<usize>::zeros((batch_size, ox, oy, oc, 2));
+
+        for n in 0..batch_size {
+            for f in 0..oc {
+                for y in 0..oy {
+                    for x in 0..ox {
+                        output[[n, x, y, f]] = f32::NEG_INFINITY;
+
+                        for ky in 0..self.kernel_size.1 {
+                            for kx in 0..self.kernel_size.0 {
+                                let index: (usize, usize) =
+                                    (x * self.stride.0 + kx, y * self.stride.1 + ky);
+                                let value: f32 = input[[n, index.0, index.1, f]];
+
+                                if value > output[[n, x, y, f]] {
+                                    output[[n, x, y, f]] = value;
+                                    highest_indices[[n, x, y, f, 0]] = index.0;
+                                    highest_indices[[n, x, y, f, 1]] = index.1;
+                                }
                             }
                         }
                     }
                 }
             }
         }
-        output
+
+        (output, highest_indices)
     }
 
-    pub fn back_propagate(&mut self, error: Array3<f32>) -> Array3<f32> {
-        let mut prev_error: Array3<f32> = Array3::<f32>::zeros(self.input_size);
+    pub fn back_propagate(&mut self, error: Array4<f32>) -> Array4<f32> {
+        let batch_size = error.dim().0;
+        let mut prev_error: Array4<f32> = Array4::<f32>::zeros((
+            batch_size,
+            self.input_size.0,
+            self.input_size.1,
+            self.input_size.2,
+        ));
 
-        for f in 0..self.output_size.2 {
-            for y in 0..self.output_size.1 {
-                for x in 0..self.output_size.0 {
-                    let hx: usize = self.highest_indices[[x, y, f, 0]];
-                    let hy: usize = self.highest_indices[[x, y, f, 1]];
-                    prev_error[[hx, hy, f]] = error[[x, y, f]];
+        for n in 0..batch_size {
+            for f in 0..self.output_size.2 {
+                for y in 0..self.output_size.1 {
+                    for x in 0..self.output_size.0 {
+                        let hx: usize = self.highest_indices[[n, x, y, f, 0]];
+                        let hy: usize = self.highest_indices[[n, x, y, f, 1]];
+                        // Overlapping windows can share the same maximum
+                        prev_error[[n, hx, hy, f]] += error[[n, x, y, f]];
+                    }
                 }
             }
         }
@@ -1,14 +1,30 @@
-use ndarray::{Array1, Array2, Array4};
+use ndarray::{Array, Dimension, Ix1, Ix2, Ix4, ShapeBuilder, Zip};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Formatter};
 
+/// Keeps the adaptive divisions away from zero
+const EPSILON: f32 = 1e-8;
+/// AdaDelta needs a larger epsilon to get its step sizes started
+const ADADELTA_EPSILON: f32 = 1e-6;
+
 // Define the Optimizer Algorithm Enum
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum OptimizerAlg {
     SGD(f32),
     Momentum(f32, f32),
     RMSProp(f32, f32),
     Adam(f32, f32, f32),
+    /// Learning rate, scaled by the root of the sum of all past squared gradients
+    AdaGrad(f32),
+    /// Learning rate (1.0 for the original algorithm) and rho
+    AdaDelta(f32, f32),
+    /// Learning rate and mu, taking the step from the look-ahead velocity
+    Nesterov(f32, f32),
+    /// Adam with decoupled weight decay: learning rate, beta1, beta2, weight decay
+    AdamW(f32, f32, f32, f32),
+    /// Adam with weight decay and a per-tensor trust ratio: learning rate, beta1, beta2,
+    /// weight decay
+    LAMB(f32, f32, f32, f32),
 }
 
 impl Debug for OptimizerAlg {
@@ -28,112 +44,110 @@ impl Debug for OptimizerAlg {
                     lr, beta1, beta2
                 )
             }
+            OptimizerAlg::AdaGrad(lr) => write!(f, "AdaGrad: Learning Rate = {}", lr),
+            OptimizerAlg::AdaDelta(lr, rho) => {
+                write!(f, "AdaDelta: Learning Rate = {}, Rho = {}", lr, rho)
+            }
+            OptimizerAlg::Nesterov(lr, mu) => {
+                write!(f, "Nesterov: Learning Rate = {}, Mu = {}", lr, mu)
+            }
+            OptimizerAlg::AdamW(lr, beta1, beta2, weight_decay) => {
+                write!(
+                    f,
+                    "AdamW: Learning Rate = {}, Beta1 = {}, Beta2 = {}, Weight Decay = {}",
+                    lr, beta1, beta2, weight_decay
+                )
+            }
+            OptimizerAlg::LAMB(lr, beta1, beta2, weight_decay) => {
+                write!(
+                    f,
+                    "LAMB: Learning Rate = {}, Beta1 = {}, Beta2 = {}, Weight Decay = {}",
+                    lr, beta1, beta2, weight_decay
+                )
+            }
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Optimizer2D {
-    pub alg: OptimizerAlg,
-    pub momentum1: Array2<f32>,
-    pub momentum2: Array2<f32>,
-    pub t: i32,
-    pub beta1_done: bool,
-    pub beta2_done: bool,
-}
-
-impl Optimizer2D {
-    pub fn new(alg: OptimizerAlg, input_size: usize, output_size: usize) -> Optimizer2D {
-        let momentum1 = Array2::<f32>::zeros((output_size, input_size));
-        let momentum2 = Array2::<f32>::zeros((output_size, input_size));
-        let t = 0;
-        let beta1_done = false;
-        let beta2_done = false;
-
-        Optimizer2D {
-            alg,
-            momentum1,
-            momentum2,
-            t,
-            beta1_done,
-            beta2_done,
+impl OptimizerAlg {
+    pub fn learning_rate(&self) -> f32 {
+        match *self {
+            OptimizerAlg::SGD(lr) => lr,
+            OptimizerAlg::Momentum(lr, _) => lr,
+            OptimizerAlg::RMSProp(lr, _) => lr,
+            OptimizerAlg::Adam(lr, _, _) => lr,
+            OptimizerAlg::AdaGrad(lr) => lr,
+            OptimizerAlg::AdaDelta(lr, _) => lr,
+            OptimizerAlg::Nesterov(lr, _) => lr,
+            OptimizerAlg::AdamW(lr, _, _, _) => lr,
+            OptimizerAlg::LAMB(lr, _, _, _) => lr,
         }
     }
 
-    pub fn weight_changes(&mut self, gradients: &Array2<f32>) -> Array2<f32> {
-        match self.alg {
-            OptimizerAlg::SGD(lr) => gradients * lr,
-            OptimizerAlg::Momentum(lr, mu) => {
-                self.momentum1 = &self.momentum1 * mu + gradients;
-                &self.momentum1 * lr
-            }
-            OptimizerAlg::RMSProp(lr, rho) => {
-                self.momentum1 = &self.momentum1 * rho;
-                self.momentum1 += &(gradients.mapv(|x| x.powi(2) as f32) * (1.0 - rho));
-                gradients * lr / (self.momentum1.mapv(|x| x.sqrt()) + 1e-8)
-            }
-            OptimizerAlg::Adam(lr, beta1, beta2) => {
-                self.t += 1;
-                self.momentum1 = &self.momentum1 * beta1;
-                self.momentum1 += &(gradients.mapv(|x| x * (1.0 - beta1)));
-                self.momentum2 = &self.momentum2 * beta2;
-                self.momentum2 += &(gradients.mapv(|x| x.powi(2) * (1.0 - beta2) as f32));
-                let biased_beta1 = if self.beta1_done {
-                    0.0
-                } else {
-                    let pow = beta1.powi(self.t);
-                    if pow < 0.001 {
-                        self.beta1_done = true;
-                    }
-                    pow
-                };
-                let biased_beta2 = if self.beta2_done {
-                    0.0
-                } else {
-                    let pow = beta2.powi(self.t);
-                    if pow < 0.001 {
-                        self.beta2_done = true;
-                    }
-                    pow
-                };
-
-                let weight_velocity_corrected = &self.momentum1 / (1.0 - biased_beta1);
-                let weight_velocity2_corrected = &self.momentum2 / (1.0 - biased_beta2);
-                &weight_velocity_corrected * lr
-                    / (weight_velocity2_corrected.mapv(|x| x.sqrt()) + 1e-8)
-            }
+    /// The same algorithm and parameters with another learning rate
+    pub fn with_learning_rate(&self, lr: f32) -> OptimizerAlg {
+        match *self {
+            OptimizerAlg::SGD(_) => OptimizerAlg::SGD(lr),
+            OptimizerAlg::Momentum(_, mu) => OptimizerAlg::Momentum(lr, mu),
+            OptimizerAlg::RMSProp(_, rho) => OptimizerAlg::RMSProp(lr, rho),
+            OptimizerAlg::Adam(_, beta1, beta2) => OptimizerAlg::Adam(lr, beta1, beta2),
+            OptimizerAlg::AdaGrad(_) => OptimizerAlg::AdaGrad(lr),
+            OptimizerAlg::AdaDelta(_, rho) => OptimizerAlg::AdaDelta(lr, rho),
+            OptimizerAlg::Nesterov(_, mu) => OptimizerAlg::Nesterov(lr, mu),
+            OptimizerAlg::AdamW(_, beta1, beta2, wd) => OptimizerAlg::AdamW(lr, beta1, beta2, wd),
+            OptimizerAlg::LAMB(_, beta1, beta2, wd) => OptimizerAlg::LAMB(lr, beta1, beta2, wd),
         }
     }
 
-    pub fn bias_changes(&mut self, gradients: &Array1<f32>) -> Array1<f32> {
-        match self.alg {
-            OptimizerAlg::SGD(lr) => gradients * lr,
-            OptimizerAlg::Momentum(lr, _) => gradients * lr,
-            OptimizerAlg::RMSProp(lr, _) => gradients * lr,
-            OptimizerAlg::Adam(lr, _, _) => gradients * lr,
+    /// The same algorithm without its built-in weight decay, used for biases
+    pub fn without_weight_decay(&self) -> OptimizerAlg {
+        match *self {
+            OptimizerAlg::AdamW(lr, beta1, beta2, _) => OptimizerAlg::AdamW(lr, beta1, beta2, 0.0),
+            OptimizerAlg::LAMB(lr, beta1, beta2, _) => OptimizerAlg::LAMB(lr, beta1, beta2, 0.0),
+            alg => alg,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Optimizer4D {
+/// Optimizer state for one parameter tensor of any dimension, e.g. `Optimizer<Ix1>` for
+/// biases and `Optimizer<Ix4>` for convolution kernels.
+///
+/// The gradients passed in are the negative gradients accumulated by the layers, so the
+/// returned changes are added to the parameters.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Optimizer<D: Dimension> {
     pub alg: OptimizerAlg,
-    pub momentum1: Array4<f32>,
-    pub momentum2: Array4<f32>,
+    /// Velocity for the momentum methods, first moment for the Adam family and the mean
+    /// squared update for AdaDelta
+    pub momentum1: Array<f32, D>,
+    /// Running (or, for AdaGrad, summed) squared gradients
+    pub momentum2: Array<f32, D>,
     pub t: i32,
     pub beta1_done: bool,
     pub beta2_done: bool,
 }
 
-impl Optimizer4D {
-    pub fn new(alg: OptimizerAlg, size: (usize, usize, usize, usize)) -> Optimizer4D {
-        let momentum1 = Array4::<f32>::zeros(size);
-        let momentum2 = Array4::<f32>::zeros(size);
+pub type Optimizer1D = Optimizer<Ix1>;
+pub type Optimizer2D = Optimizer<Ix2>;
+pub type Optimizer4D = Optimizer<Ix4>;
+
+/// Models saved before biases had their own optimizer load with an empty one, which the
+/// layers replace in `zero`
+impl<D: Dimension> Default for Optimizer<D> {
+    fn default() -> Self {
+        Optimizer::new(OptimizerAlg::SGD(0.0), D::zeros(D::NDIM.unwrap_or(0)))
+    }
+}
+
+impl<D: Dimension> Optimizer<D> {
+    pub fn new<Sh: ShapeBuilder<Dim = D>>(alg: OptimizerAlg, shape: Sh) -> Optimizer<D> {
+        let momentum1 = Array::<f32, D>::zeros(shape);
+        let momentum2 = Array::<f32, D>::zeros(momentum1.raw_dim());
         let t = 0;
         let beta1_done = false;
         let beta2_done = false;
 
-        Optimizer4D {
+        Optimizer {
             alg,
             momentum1,
             momentum2,
@@ -143,48 +157,91 @@ impl Optimizer4D {
         }
     }
 
-    pub fn weight_changes(&mut self, gradients: &Array4<f32>) -> Array4<f32> {
+    /// Changes to add to `params` given their averaged negative `gradients`
+    pub fn weight_changes(
+        &mut self,
+        params: &Array<f32, D>,
+        gradients: &Array<f32, D>,
+    ) -> Array<f32, D> {
         match self.alg {
             OptimizerAlg::SGD(lr) => gradients * lr,
             OptimizerAlg::Momentum(lr, mu) => {
                 self.momentum1 = &self.momentum1 * mu + gradients;
                 &self.momentum1 * lr
             }
+            OptimizerAlg::Nesterov(lr, mu) => {
+                self.momentum1 = &self.momentum1 * mu + gradients;
+                (gradients + &(&self.momentum1 * mu)) * lr
+            }
             OptimizerAlg::RMSProp(lr, rho) => {
                 self.momentum1 = &self.momentum1 * rho;
-                self.momentum1 += &(gradients.mapv(|x| x.powi(2) as f32) * (1.0 - rho));
-                gradients * lr / (self.momentum1.mapv(|x| x.sqrt()) + 1e-8)
+                self.momentum1 += &(gradients.mapv(|x| x.powi(2)) * (1.0 - rho));
+                gradients * lr / (self.momentum1.mapv(|x| x.sqrt()) + EPSILON)
             }
-            OptimizerAlg::Adam(lr, beta1, beta2) => {
-                self.t += 1;
-                self.momentum1 = &self.momentum1 * beta1;
-                self.momentum1 += &(gradients.mapv(|x| x * (1.0 - beta1)));
-                self.momentum2 = &self.momentum2 * beta2;
-                self.momentum2 += &(gradients.mapv(|x| x.powi(2) * (1.0 - beta2) as f32));
-                let biased_beta1 = if self.beta1_done {
-                    0.0
-                } else {
-                    let pow = beta1.powi(self.t);
-                    if pow < 0.001 {
-                        self.beta1_done = true;
-                    }
-                    pow
-                };
-                let biased_beta2 = if self.beta2_done {
-                    0.0
+            OptimizerAlg::AdaGrad(lr) => {
+                self.momentum2 += &gradients.mapv(|x| x.powi(2));
+                gradients * lr / (self.momentum2.mapv(|x| x.sqrt()) + EPSILON)
+            }
+            OptimizerAlg::AdaDelta(lr, rho) => {
+                self.momentum2 = &self.momentum2 * rho;
+                self.momentum2 += &(gradients.mapv(|x| x.powi(2)) * (1.0 - rho));
+                let mut step = gradients.clone();
+                Zip::from(&mut step)
+                    .and(&self.momentum1)
+                    .and(&self.momentum2)
+                    .for_each(|g, &dx2, &g2| {
+                        *g *= (dx2 + ADADELTA_EPSILON).sqrt() / (g2 + ADADELTA_EPSILON).sqrt()
+                    });
+                self.momentum1 = &self.momentum1 * rho;
+                self.momentum1 += &(step.mapv(|x| x.powi(2)) * (1.0 - rho));
+                step * lr
+            }
+            OptimizerAlg::Adam(lr, beta1, beta2) => self.adam_step(gradients, beta1, beta2) * lr,
+            OptimizerAlg::AdamW(lr, beta1, beta2, weight_decay) => {
+                (self.adam_step(gradients, beta1, beta2) - params * weight_decay) * lr
+            }
+            OptimizerAlg::LAMB(lr, beta1, beta2, weight_decay) => {
+                let step = self.adam_step(gradients, beta1, beta2) - params * weight_decay;
+                let param_norm = params.mapv(|x| x.powi(2)).sum().sqrt();
+                let step_norm = step.mapv(|x| x.powi(2)).sum().sqrt();
+                let trust_ratio = if param_norm > 0.0 && step_norm > 0.0 {
+                    param_norm / step_norm
                 } else {
-                    let pow = beta2.powi(self.t);
-                    if pow < 0.001 {
-                        self.beta2_done = true;
-                    }
-                    pow
+                    1.0
                 };
-
-                let weight_velocity_corrected = &self.momentum1 / (1.0 - biased_beta1);
-                let weight_velocity2_corrected = &self.momentum2 / (1.0 - biased_beta2);
-                &weight_velocity_corrected * lr
-                    / (weight_velocity2_corrected.mapv(|x| x.sqrt()) + 1e-8)
+                step * (lr * trust_ratio)
             }
         }
     }
+
+    /// Updates the Adam moments and returns the bias-corrected step before the learning rate
+    fn adam_step(&mut self, gradients: &Array<f32, D>, beta1: f32, beta2: f32) -> Array<f32, D> {
+        self.t += 1;
+        self.momentum1 = &self.momentum1 * beta1;
+        self.momentum1 += &(gradients.mapv(|x| x * (1.0 - beta1)));
+        self.momentum2 = &self.momentum2 * beta2;
+        self.momentum2 += &(gradients.mapv(|x| x.powi(2) * (1.0 - beta2)));
+        let biased_beta1 = if self.beta1_done {
+            0.0
+        } else {
+            let pow = beta1.powi(self.t);
+            if pow < 0.001 {
+                self.beta1_done = true;
+            }
+            pow
+        };
+        let biased_beta2 = if self.beta2_done {
+            0.0
+        } else {
+            let pow = beta2.powi(self.t);
+            if pow < 0.001 {
+                self.beta2_done = true;
+            }
+            pow
+        };
+
+        let weight_velocity_corrected = &self.momentum1 / (1.0 - biased_beta1);
+        let weight_velocity2_corrected = &self.momentum2 / (1.0 - biased_beta2);
+        weight_velocity_corrected / (weight_velocity2_corrected.mapv(|x| x.sqrt()) + EPSILON)
+    }
 }
@@ -0,0 +1,159 @@
+use crate::early_stopping::Metric;
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Where a model writes its files and what it calls them.
+///
+/// The templates give file names without extensions. `{name}` is replaced by the model
+/// name, `{time}` by its creation time in milliseconds since the epoch and `{epoch}` by
+/// the number of epochs it has trained.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OutputConfig {
+    /// Created when missing
+    pub dir: PathBuf,
+    /// Names the `.json`, `.bin` and `.txt` files written by `CNN::save`
+    pub model_template: String,
+    /// Names the checkpoints written after every epoch
+    pub checkpoint_template: String,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            dir: PathBuf::from("models"),
+            model_template: String::from("{name}_{time}"),
+            checkpoint_template: String::from("{name}_{time}_epoch{epoch}"),
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Path of a model file with the given extension
+    pub fn model_path(&self, name: &str, time: u128, epoch: usize, extension: &str) -> PathBuf {
+        self.path(&self.model_template, name, time, epoch, extension)
+    }
+
+    /// Path of the checkpoint written after `epoch` epochs
+    pub fn checkpoint_path(&self, name: &str, time: u128, epoch: usize) -> PathBuf {
+        self.path(&self.checkpoint_template, name, time, epoch, "json")
+    }
+
+    fn path(
+        &self,
+        template: &str,
+        name: &str,
+        time: u128,
+        epoch: usize,
+        extension: &str,
+    ) -> PathBuf {
+        let stem = template
+            .replace("{name}", name)
+            .replace("{time}", &time.to_string())
+            .replace("{epoch}", &epoch.to_string());
+        self.dir.join(format!("{}.{}", stem, extension))
+    }
+}
+
+/// Which of the per-epoch checkpoints stay on disk. The last `keep_last`, and always the
+/// latest, are kept along with the `keep_best` that scored best on `metric`, the rest are
+/// deleted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Retention {
+    pub keep_last: usize,
+    pub keep_best: usize,
+    pub metric: Metric,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            keep_last: 1,
+            keep_best: 0,
+            metric: Metric::ValidationAccuracy,
+        }
+    }
+}
+
+/// A checkpoint written during training
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub path: PathBuf,
+    /// Epochs trained when it was written
+    pub epoch: usize,
+    /// Value of the retention metric, None when it was not available
+    pub metric: Option<f32>,
+}
+
+impl Retention {
+    /// Whether choosing the best checkpoints needs a validation set
+    pub fn needs_validation(&self) -> bool {
+        self.keep_best > 0 && self.metric.needs_validation()
+    }
+
+    /// Whether choosing the best checkpoints needs a test set
+    pub fn needs_test(&self) -> bool {
+        self.keep_best > 0 && self.metric.needs_test()
+    }
+
+    /// Removes the checkpoints this policy does not keep from `checkpoints`, which are in
+    /// the order they were written, and returns them
+    pub fn prune(&self, checkpoints: &mut Vec<Checkpoint>) -> Vec<Checkpoint> {
+        let mut keep = vec![false; checkpoints.len()];
+        let first_kept = checkpoints.len().saturating_sub(self.keep_last.max(1));
+        keep[first_kept..].fill(true);
+
+        let mut ranked: Vec<(usize, f32)> = checkpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(i, checkpoint)| checkpoint.metric.map(|metric| (i, metric)))
+            .filter(|(_, metric)| !metric.is_nan())
+            .collect();
+        // Stable, so the earlier checkpoint wins a tie
+        if self.metric.higher_is_better() {
+            ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        } else {
+            ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+        }
+        for (i, _) in ranked.into_iter().take(self.keep_best) {
+            keep[i] = true;
+        }
+
+        let (kept, removed): (Vec<_>, Vec<_>) =
+            checkpoints.drain(..).zip(keep).partition(|(_, keep)| *keep);
+        *checkpoints = kept.into_iter().map(|(checkpoint, _)| checkpoint).collect();
+        removed
+            .into_iter()
+            .map(|(checkpoint, _)| checkpoint)
+            .collect()
+    }
+}
+
+/// Writes a file through `write` into a temporary file next to `path`, then renames it
+/// into place, so readers never see a partly written file
+pub fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<()>,
+{
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let written = write(&mut writer)
+        .and_then(|_| writer.flush().map_err(Into::into))
+        .and_then(|_| writer.get_ref().sync_all().map_err(Into::into));
+    drop(writer);
+    if let Err(error) = written {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(error);
+    }
+    std::fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use ndarray::{Array, Dimension};
+use serde::{Deserialize, Serialize};
+
+/// Penalties on the size of a layer's weights. Biases are never regularized.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Regularization {
+    /// Adds `l1 * sum(|w|)` to the loss
+    pub l1: f32,
+    /// Adds `l2 / 2 * sum(w^2)` to the loss, its gradient goes through the optimizer
+    pub l2: f32,
+    /// Decoupled (AdamW-style) decay, shrinking the weights by `lr * weight_decay * w` every
+    /// update outside of the optimizer. It is not part of the reported loss.
+    pub weight_decay: f32,
+}
+
+impl Regularization {
+    /// No regularization at all
+    pub fn none() -> Regularization {
+        Regularization::default()
+    }
+
+    /// Coupled L2 penalty
+    pub fn l2(l2: f32) -> Regularization {
+        Regularization {
+            l2,
+            ..Regularization::default()
+        }
+    }
+
+    /// L1 penalty
+    pub fn l1(l1: f32) -> Regularization {
+        Regularization {
+            l1,
+            ..Regularization::default()
+        }
+    }
+
+    /// Decoupled weight decay
+    pub fn weight_decay(weight_decay: f32) -> Regularization {
+        Regularization {
+            weight_decay,
+            ..Regularization::default()
+        }
+    }
+
+    /// Loss added by the L1 and L2 penalties on `weights`
+    pub fn penalty<D: Dimension>(&self, weights: &Array<f32, D>) -> f32 {
+        let mut penalty = 0.0;
+        if self.l1 != 0.0 {
+            penalty += self.l1 * weights.mapv(f32::abs).sum();
+        }
+        if self.l2 != 0.0 {
+            penalty += self.l2 / 2.0 * weights.mapv(|w| w * w).sum();
+        }
+        penalty
+    }
+
+    /// Adds the penalties to averaged weight changes, which hold the negative gradient
+    pub fn add_gradients<D: Dimension>(
+        &self,
+        weights: &Array<f32, D>,
+        changes: &mut Array<f32, D>,
+    ) {
+        if self.l1 != 0.0 {
+            *changes -= &weights.mapv(|w| self.l1 * w.signum() * (w != 0.0) as usize as f32);
+        }
+        if self.l2 != 0.0 {
+            *changes -= &(weights * self.l2);
+        }
+    }
+
+    /// Change to the weights from decoupled weight decay at learning rate `lr`
+    pub fn decay<D: Dimension>(&self, weights: &Array<f32, D>, lr: f32) -> Option<Array<f32, D>> {
+        if self.weight_decay == 0.0 {
+            return None;
+        }
+        Some(weights * (-lr * self.weight_decay))
+    }
+}
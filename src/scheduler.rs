@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// How the learning rate of the optimizer changes over training.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum LrSchedule {
+    /// Keep the optimizer's learning rate for the whole run
+    #[default]
+    Constant,
+    /// Multiply the rate by `gamma` every `step_size` epochs
+    StepDecay { step_size: usize, gamma: f32 },
+    /// Multiply the rate by `gamma` after every epoch
+    Exponential { gamma: f32 },
+    /// Anneal the rate along a cosine down to `min_lr` over `period` epochs, then restart
+    /// with a period `mult` times longer. Updated every minibatch.
+    CosineWarmRestarts {
+        period: usize,
+        mult: usize,
+        min_lr: f32,
+    },
+    /// Ramp the rate linearly up to the optimizer's rate over the first `warmup_steps`
+    /// minibatches, then keep it
+    LinearWarmup { warmup_steps: usize },
+    /// Multiply the rate by `factor` once the test accuracy has not improved for more
+    /// than `patience` epochs in a row, never going below `min_lr`
+    ReduceOnPlateau {
+        factor: f32,
+        patience: usize,
+        min_lr: f32,
+    },
+}
+
+/// A schedule along with how far training has progressed through it. It is saved with
+/// the model, so training a loaded model carries on where the schedule left off.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LrScheduler {
+    pub schedule: LrSchedule,
+    /// Epochs completed
+    pub epoch: usize,
+    /// Minibatches completed in the current epoch
+    pub epoch_step: usize,
+    /// Minibatches completed overall
+    pub step: usize,
+    /// Current reduction applied by `ReduceOnPlateau`
+    pub plateau_scale: f32,
+    /// Best test accuracy seen by `ReduceOnPlateau`
+    pub best: Option<f32>,
+    /// Epochs since `best` last improved
+    pub bad_epochs: usize,
+}
+
+impl Default for LrScheduler {
+    fn default() -> Self {
+        LrScheduler::new(LrSchedule::Constant)
+    }
+}
+
+impl LrScheduler {
+    pub fn new(schedule: LrSchedule) -> LrScheduler {
+        LrScheduler {
+            schedule,
+            epoch: 0,
+            epoch_step: 0,
+            step: 0,
+            plateau_scale: 1.0,
+            best: None,
+            bad_epochs: 0,
+        }
+    }
+
+    /// Learning rate for the next minibatch of an epoch with `steps_per_epoch` minibatches
+    pub fn learning_rate(&self, base_lr: f32, steps_per_epoch: usize) -> f32 {
+        match self.schedule {
+            LrSchedule::Constant => base_lr,
+            LrSchedule::StepDecay { step_size, gamma } => {
+                base_lr * gamma.powi((self.epoch / step_size.max(1)) as i32)
+            }
+            LrSchedule::Exponential { gamma } => base_lr * gamma.powi(self.epoch as i32),
+            LrSchedule::CosineWarmRestarts {
+                period,
+                mult,
+                min_lr,
+            } => {
+                // Position in epochs, including the fraction of the current one
+                let mut t =
+                    self.epoch as f32 + self.epoch_step as f32 / steps_per_epoch.max(1) as f32;
+                let mut cycle = period.max(1) as f32;
+                while t >= cycle {
+                    t -= cycle;
+                    cycle *= mult.max(1) as f32;
+                }
+                min_lr + (base_lr - min_lr) * (1.0 + (PI * t / cycle).cos()) / 2.0
+            }
+            LrSchedule::LinearWarmup { warmup_steps } => {
+                base_lr * ((self.step + 1) as f32 / warmup_steps.max(1) as f32).min(1.0)
+            }
+            LrSchedule::ReduceOnPlateau { min_lr, .. } => {
+                (base_lr * self.plateau_scale).max(min_lr)
+            }
+        }
+    }
+
+    /// Records a finished minibatch
+    pub fn end_step(&mut self) {
+        self.epoch_step += 1;
+        self.step += 1;
+    }
+
+    /// Records a finished epoch along with its held-out accuracy, None when there is no set
+    /// to judge plateaus on
+    pub fn end_epoch(&mut self, test_accuracy: Option<f32>) {
+        self.epoch += 1;
+        self.epoch_step = 0;
+
+        if let (
+            LrSchedule::ReduceOnPlateau {
+                factor, patience, ..
+            },
+            Some(test_accuracy),
+        ) = (self.schedule, test_accuracy)
+        {
+            match self.best {
+                Some(best) if test_accuracy <= best => {
+                    self.bad_epochs += 1;
+                    if self.bad_epochs > patience {
+                        self.plateau_scale *= factor;
+                        self.bad_epochs = 0;
+                    }
+                }
+                _ => {
+                    self.best = Some(test_accuracy);
+                    self.bad_epochs = 0;
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use image::ImageReader;
 use ndarray::{Array1, Array2, Array3};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
@@ -11,20 +11,62 @@ pub enum TrainImage {
     Path(PathBuf),
 }
 
-/// Holds training and testing data along with metadata.
+/// Holds training, validation and testing data along with metadata.
+///
+/// The validation set is used to select models during training, the test set only to
+/// report on them. It can be filled in directly or split off the training set with
+/// `mnist_impl::split_validation`.
 #[derive(Serialize, Deserialize, Default)]
 pub struct TrainingData {
     pub trn_img: Vec<TrainImage>,
     pub trn_lbl: Vec<usize>,
+    #[serde(default)]
+    pub val_img: Vec<TrainImage>,
+    #[serde(default)]
+    pub val_lbl: Vec<usize>,
     pub tst_img: Vec<TrainImage>,
     pub tst_lbl: Vec<usize>,
     pub rows: usize,
     pub cols: usize,
     pub trn_size: usize,
+    #[serde(default)]
+    pub val_size: usize,
     pub tst_size: usize,
     pub classes: HashMap<usize, usize>,
 }
 
+/// Part of the training set held out for validation.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ValidationSplit {
+    /// Fraction of the training samples moved to the validation set
+    pub fraction: f32,
+    /// Take the fraction from every label separately, so both sets keep the label balance
+    pub stratified: bool,
+}
+
+/// Reads a kernel size or stride saved as an `(x, y)` pair or, by models from before the
+/// two axes could differ, as a single number used for both. Binary models are always
+/// read as a pair.
+pub fn deserialize_pair<'de, D>(deserializer: D) -> Result<(usize, usize), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Pair {
+        Square(usize),
+        Pair(usize, usize),
+    }
+
+    if !deserializer.is_human_readable() {
+        return <(usize, usize)>::deserialize(deserializer);
+    }
+    Ok(match Pair::deserialize(deserializer)? {
+        Pair::Square(size) => (size, size),
+        Pair::Pair(x, y) => (x, y),
+    })
+}
+
 /// Computes the outer product of two vectors.
 pub fn outer(x: Array1<f32>, y: Array1<f32>) -> Array2<f32> {
     Array2::from_shape_fn((x.len(), y.len()), |(i, j)| x[i] * y[j])
@@ -39,7 +81,10 @@ pub enum SavingStrategy {
     EveryEpoch(bool),
     EveryNthEpoch(bool, f32),
     BestTrainingAccuracy(bool),
+    /// Selects on the test set, prefer `BestValidationAccuracy` to keep the test set unseen
     BestTestingAccuracy(bool),
+    /// Needs a validation set in the training data
+    BestValidationAccuracy(bool),
     Never,
 }
 
@@ -54,11 +99,12 @@ pub fn load_image(path: &Path) -> Result<Array3<f32>, String> {
     let (rows, cols) = (img.height() as usize, img.width() as usize);
     let mut array = Array3::<f32>::zeros((rows, cols, 3));
 
+    // Pixels are indexed (column, row), the array is (row, column, channel)
     for (x, y, pixel) in img.enumerate_pixels() {
         let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
-        array[[x as usize, y as usize, 0]] = r / 255.0;
-        array[[x as usize, y as usize, 1]] = g / 255.0;
-        array[[x as usize, y as usize, 2]] = b / 255.0;
+        array[[y as usize, x as usize, 0]] = r / 255.0;
+        array[[y as usize, x as usize, 1]] = g / 255.0;
+        array[[y as usize, x as usize, 2]] = b / 255.0;
     }
 
     Ok(array)
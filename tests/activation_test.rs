@@ -49,9 +49,27 @@ mod tests {
 
     #[test]
     fn test_softmax_derivative() {
-        let input = array![1.0, 2.0, 3.0];
+        // Like sigmoid, the derivative is taken from the softmax output
+        let input = array![0.2, 0.3, 0.5];
         let output = backward(input.clone(), Activation::Softmax);
-        let expected = array![1.0, 1.0, 1.0]; // Currently softmax_derivative just returns ones
+        let expected = array![0.16, 0.21, 0.25];
+        for (a, b) in output.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_linear_forward() {
+        let input = array![-1.0, 0.0, 1.0, 2.0];
+        let output = forward(input.clone(), Activation::Linear);
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_linear_backward() {
+        let input = array![-1.0, 0.0, 1.0, 2.0];
+        let expected = array![1.0, 1.0, 1.0, 1.0];
+        let output = backward(input.clone(), Activation::Linear);
         assert_eq!(output, expected);
     }
 }
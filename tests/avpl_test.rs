@@ -0,0 +1,101 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::avpl::{AvplLayer, GlobalAvplLayer};
+    use ndarray::Array4;
+
+    fn sample_input(size: (usize, usize, usize, usize)) -> Array4<f32> {
+        Array4::<f32>::from_shape_fn(size, |(n, x, y, c)| {
+            ((n * 17 + x * 7 + y * 3 + c * 5) as f32).sin()
+        })
+    }
+
+    #[test]
+    fn test_avpl_layer_output_size() {
+        let avpl_layer = AvplLayer::new((28, 28, 4), (2, 2), (2, 2));
+        assert_eq!(avpl_layer.output_size, (14, 14, 4));
+
+        let overlapping = AvplLayer::new((9, 6, 1), (3, 2), (2, 1));
+        assert_eq!(overlapping.output_size, (4, 5, 1));
+    }
+
+    #[test]
+    fn test_avpl_layer_forward_propagation() {
+        let mut avpl_layer = AvplLayer::new((2, 4, 1), (2, 2), (2, 2));
+        let input = Array4::<f32>::from_shape_fn((1, 2, 4, 1), |(_, x, y, _)| (x * 4 + y) as f32);
+
+        let output = avpl_layer.forward_propagate(input);
+
+        assert_eq!(output.shape(), &[1, 1, 2, 1]);
+        assert_eq!(output[[0, 0, 0, 0]], 2.5);
+        assert_eq!(output[[0, 0, 1, 0]], 4.5);
+    }
+
+    #[test]
+    fn test_avpl_layer_gradients() {
+        // The loss sum(output * weights) is linear, so its input gradient is exact
+        let input_size = (7, 6, 2);
+        let mut avpl_layer = AvplLayer::new(input_size, (3, 2), (2, 1));
+        let input = sample_input((2, input_size.0, input_size.1, input_size.2));
+        let (ox, oy, oc) = avpl_layer.output_size;
+        let weights = sample_input((2, ox, oy, oc)).mapv(f32::cos);
+        avpl_layer.forward_propagate(input.clone());
+        let prev_error = avpl_layer.back_propagate(weights.clone());
+
+        let eps = 1e-2;
+        for idx in ndarray::indices(input.dim()) {
+            let (mut plus, mut minus) = (input.clone(), input.clone());
+            plus[idx] += eps;
+            minus[idx] -= eps;
+            let numeric = ((avpl_layer.forward_propagate(plus) * &weights).sum()
+                - (avpl_layer.forward_propagate(minus) * &weights).sum())
+                / (2.0 * eps);
+            assert!((numeric - prev_error[idx]).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_global_avpl_layer_forward_propagation() {
+        let mut global_avpl_layer = GlobalAvplLayer::new((2, 3, 2));
+        let input = Array4::<f32>::from_shape_fn((1, 2, 3, 2), |(_, x, y, c)| {
+            ((x * 3 + y) * (c + 1)) as f32
+        });
+
+        let output = global_avpl_layer.forward_propagate(input);
+
+        assert_eq!(global_avpl_layer.output_size, (1, 1, 2));
+        assert_eq!(output[[0, 0, 0, 0]], 2.5);
+        assert_eq!(output[[0, 0, 0, 1]], 5.0);
+    }
+
+    #[test]
+    fn test_global_avpl_layer_backward_propagation() {
+        let mut global_avpl_layer = GlobalAvplLayer::new((2, 3, 2));
+        global_avpl_layer.forward_propagate(Array4::<f32>::ones((1, 2, 3, 2)));
+
+        let error = Array4::<f32>::from_shape_fn((1, 1, 1, 2), |(_, _, _, c)| (c + 1) as f32 * 6.0);
+        let prev_error = global_avpl_layer.back_propagate(error);
+
+        assert_eq!(prev_error.shape(), &[1, 2, 3, 2]);
+        assert!(prev_error
+            .slice(ndarray::s![.., .., .., 0])
+            .iter()
+            .all(|&x| x == 1.0));
+        assert!(prev_error
+            .slice(ndarray::s![.., .., .., 1])
+            .iter()
+            .all(|&x| x == 2.0));
+    }
+
+    #[test]
+    fn test_avpl_layer_serialization() {
+        let avpl_layer = AvplLayer::new((8, 8, 3), (2, 2), (2, 2));
+        let json = serde_json::to_string(&avpl_layer).unwrap();
+        let loaded: AvplLayer = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.output_size, avpl_layer.output_size);
+
+        let global_avpl_layer = GlobalAvplLayer::new((8, 8, 3));
+        let json = serde_json::to_string(&global_avpl_layer).unwrap();
+        let loaded: GlobalAvplLayer = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.output_size, (1, 1, 3));
+    }
+}
@@ -0,0 +1,38 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::clipping::GradientClipping;
+    use ndarray::array;
+
+    #[test]
+    fn test_no_clipping() {
+        let mut changes = array![[30.0, -40.0]];
+        GradientClipping::None.apply(&mut changes, 50.0, 1);
+
+        assert_eq!(changes, array![[30.0, -40.0]]);
+    }
+
+    #[test]
+    fn test_clip_by_value() {
+        // Summed over 2 samples, so the averaged gradients [3, -0.5, -4] are clamped to 1
+        let mut changes = array![6.0, -1.0, -8.0];
+        GradientClipping::Value(1.0).apply(&mut changes, 0.0, 2);
+
+        assert_eq!(changes, array![2.0, -1.0, -2.0]);
+    }
+
+    #[test]
+    fn test_clip_by_norm() {
+        let mut changes = array![[30.0, -40.0]];
+        GradientClipping::Norm(5.0).apply(&mut changes, 50.0, 1);
+
+        assert_eq!(changes, array![[3.0, -4.0]]);
+    }
+
+    #[test]
+    fn test_norm_below_limit_is_unchanged() {
+        let mut changes = array![[3.0, -4.0]];
+        GradientClipping::Norm(10.0).apply(&mut changes, 5.0, 1);
+
+        assert_eq!(changes, array![[3.0, -4.0]]);
+    }
+}
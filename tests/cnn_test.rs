@@ -1,10 +1,21 @@
 #[cfg(test)]
 mod tests {
     use conv_nn::activation::Activation;
+    use conv_nn::clipping::GradientClipping;
     use conv_nn::cnn::*;
+    use conv_nn::conv_layers::Padding;
+    use conv_nn::divergence::{DivergenceAction, DivergenceGuard};
+    use conv_nn::early_stopping::{EarlyStopping, Metric};
+    use conv_nn::error::Error;
+    use conv_nn::layer::Layer;
+    use conv_nn::loss::Loss;
     use conv_nn::optimizer::OptimizerAlg;
-    use conv_nn::utils::{TrainImage, TrainingData};
-    use ndarray::Array3;
+    use conv_nn::output::{OutputConfig, Retention};
+    use conv_nn::regularization::Regularization;
+    use conv_nn::scheduler::LrSchedule;
+    use conv_nn::utils::{SavingStrategy, TrainImage, TrainingData, ValidationSplit};
+    use ndarray::{Array3, Array4};
+    use std::path::PathBuf;
 
     fn mock_training_data() -> TrainingData {
         // Create a mock TrainingData struct with realistic values for testing
@@ -39,19 +50,20 @@ mod tests {
             tst_lbl,
             rows: 28,
             cols: 28,
+            ..TrainingData::default()
         }
     }
 
-    fn create_mock_input() -> Array3<f32> {
-        // Create a 28x28x1 input with random values
-        Array3::<f32>::ones((28, 28, 1))
+    fn create_mock_input() -> Array4<f32> {
+        // Create a batch holding a single 28x28x1 input
+        Array4::<f32>::ones((1, 28, 28, 1))
     }
 
     fn setup_basic_cnn() -> CNN {
         let data = mock_training_data();
         let params = Hyperparameters::default();
         let mut cnn = CNN::new(data, params);
-        cnn.set_input_shape(vec![28, 28, 1]);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
         cnn
     }
 
@@ -84,7 +96,7 @@ mod tests {
         let params = Hyperparameters::default();
         let mut cnn = CNN::new(data, params);
 
-        cnn.set_input_shape(vec![28, 28, 1]);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
 
         assert_eq!(cnn.input_shape, (28, 28, 1));
     }
@@ -95,8 +107,9 @@ mod tests {
         let params = Hyperparameters::default();
         let mut cnn = CNN::new(data, params);
 
-        cnn.set_input_shape(vec![28, 28, 1]);
-        cnn.add_conv_layer(32, 3);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_conv_layer(32, (3, 3), (1, 1), Padding::Valid, Activation::Relu)
+            .unwrap();
 
         assert_eq!(cnn.layers.len(), 1);
         assert_eq!(cnn.layer_order.last().unwrap(), "conv");
@@ -108,8 +121,8 @@ mod tests {
         let params = Hyperparameters::default();
         let mut cnn = CNN::new(data, params);
 
-        cnn.set_input_shape(vec![28, 28, 1]);
-        cnn.add_dense_layer(128, Activation::Relu, None);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_dense_layer(128, Activation::Relu, None).unwrap();
 
         assert_eq!(cnn.layers.len(), 1);
         assert_eq!(cnn.layer_order.last().unwrap(), "dense");
@@ -119,8 +132,9 @@ mod tests {
     fn test_cnn_forward_propagate() {
         let mut cnn = setup_basic_cnn();
 
-        cnn.add_conv_layer(32, 3);
-        cnn.add_dense_layer(10, Activation::Softmax, None);
+        cnn.add_conv_layer(32, (3, 3), (1, 1), Padding::Valid, Activation::Relu)
+            .unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
 
         let input = create_mock_input();
         let output = cnn.forward_propagate(input, false);
@@ -138,9 +152,10 @@ mod tests {
     fn test_cnn_training() {
         let mut cnn = setup_basic_cnn();
 
-        cnn.add_conv_layer(32, 3);
-        cnn.add_mxpl_layer(2); // Add pooling layer to reduce dimensionality
-        cnn.add_dense_layer(10, Activation::Softmax, None);
+        cnn.add_conv_layer(32, (3, 3), (1, 1), Padding::Valid, Activation::Relu)
+            .unwrap();
+        cnn.add_mxpl_layer((2, 2), (2, 2)).unwrap(); // Add pooling layer to reduce dimensionality
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
 
         // Mock the training process instead of actually training
         // This avoids test failures due to empty mock data
@@ -150,4 +165,1292 @@ mod tests {
         assert!(!cnn.training_history.is_empty());
         assert!(!cnn.testing_history.is_empty());
     }
+
+    #[test]
+    fn test_cnn_non_square_input() {
+        let data = mock_training_data();
+        let params = Hyperparameters::default();
+        let mut cnn = CNN::new(data, params);
+        cnn.set_input_shape(vec![32, 64, 1]).unwrap();
+
+        cnn.add_conv_layer(4, (3, 5), (1, 1), Padding::Valid, Activation::Relu)
+            .unwrap();
+        cnn.add_mxpl_layer((2, 2), (2, 2)).unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+
+        match &cnn.layers[1] {
+            Layer::Mxpl(mxpl_layer) => assert_eq!(mxpl_layer.output_size, (15, 30, 4)),
+            _ => panic!("Expected MxplLayer"),
+        }
+
+        let output = cnn.forward_propagate(Array4::<f32>::ones((1, 32, 64, 1)), false);
+        assert_eq!(output.len(), 10);
+        cnn.back_propagate(&[3], true).unwrap();
+    }
+
+    #[test]
+    fn test_cnn_pooling_layers() {
+        let mut cnn = setup_basic_cnn();
+
+        cnn.add_conv_layer(4, (3, 3), (1, 1), Padding::Same, Activation::Relu)
+            .unwrap();
+        cnn.add_mxpl_layer((3, 3), (1, 1)).unwrap();
+        cnn.add_avpl_layer((2, 2), (2, 2)).unwrap();
+        cnn.add_global_avpl_layer().unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+
+        assert_eq!(
+            cnn.layer_order,
+            vec!["conv", "mxpl", "avpl", "global_avpl", "dense"]
+        );
+        match &cnn.layers[2] {
+            Layer::Avpl(avpl_layer) => assert_eq!(avpl_layer.output_size, (13, 13, 4)),
+            _ => panic!("Expected AvplLayer"),
+        }
+        match &cnn.layers[3] {
+            Layer::GlobalAvpl(global_avpl_layer) => {
+                assert_eq!(global_avpl_layer.output_size, (1, 1, 4))
+            }
+            _ => panic!("Expected GlobalAvplLayer"),
+        }
+
+        let output = cnn.forward_propagate(create_mock_input(), false);
+        assert_eq!(output.len(), 10);
+        cnn.back_propagate(&[0], true).unwrap();
+    }
+
+    #[test]
+    fn test_cnn_forward_propagate_batch() {
+        let mut cnn = setup_basic_cnn();
+
+        cnn.add_conv_layer(4, (3, 3), (1, 1), Padding::Valid, Activation::Relu)
+            .unwrap();
+        cnn.add_mxpl_layer((2, 2), (2, 2)).unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+
+        let input = Array4::<f32>::from_shape_fn((4, 28, 28, 1), |(n, x, y, _)| {
+            ((n * 31 + x * 7 + y) as f32).sin()
+        });
+        let output = cnn.forward_propagate(input.clone(), true);
+        cnn.back_propagate(&[0, 1, 2, 3], true).unwrap();
+        cnn.update(4);
+
+        assert_eq!(output.dim(), (4, 10));
+        for row in output.rows() {
+            assert!(
+                (row.sum() - 1.0).abs() < 1e-5,
+                "Each sample's softmax should sum to 1.0"
+            );
+        }
+
+        // Each row must match forwarding that sample on its own
+        let output = cnn.forward_propagate(input.clone(), false);
+        let single =
+            cnn.forward_propagate(input.slice(ndarray::s![2..3, .., .., ..]).to_owned(), false);
+        for (a, b) in single.row(0).iter().zip(output.row(2).iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_cnn_accuracy_on_batch() {
+        let mut cnn = setup_basic_cnn();
+        cnn.add_dense_layer(3, Activation::Linear, None).unwrap();
+        cnn.forward_propagate(Array4::<f32>::zeros((2, 28, 28, 1)), false);
+
+        // Zero input leaves only the equal initial biases, and ties resolve to the first class
+        assert_eq!(cnn.get_accuracy(&[0, 0]).unwrap(), 1.0);
+        assert_eq!(cnn.get_accuracy(&[0, 1]).unwrap(), 0.5);
+    }
+
+    #[test]
+    fn test_cnn_train_on_minibatches() {
+        let data = mock_training_data();
+        let params = Hyperparameters {
+            batch_size: 10,
+            epochs: 1,
+            optimizer: OptimizerAlg::SGD(0.01),
+            verbose: false,
+            ..Hyperparameters::default()
+        };
+        let mut cnn = CNN::new(data, params);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_conv_layer(2, (3, 3), (2, 2), Padding::Valid, Activation::Relu)
+            .unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+
+        cnn.train().unwrap();
+
+        assert_eq!(cnn.training_history.len(), 1);
+        assert_eq!(cnn.testing_history.len(), 1);
+    }
+
+    /// Hyperparameters for a quiet run of one epoch in minibatches of 10
+    fn test_params() -> Hyperparameters {
+        Hyperparameters {
+            batch_size: 10,
+            epochs: 1,
+            optimizer: OptimizerAlg::SGD(0.05),
+            verbose: false,
+            ..Hyperparameters::default()
+        }
+    }
+
+    /// Builds the small networks the training tests run. By default a strided ReLU convolution
+    /// feeds a softmax over 10 classes, set up with `test_params` on `varied_training_data`.
+    struct TestCnn {
+        params: Hyperparameters,
+        data: TrainingData,
+        input_shape: Vec<usize>,
+        /// Filters, stride and activation of a 3x3 convolution
+        conv: Option<(usize, (usize, usize), Activation)>,
+        /// Adds 2x2 max pooling after the convolution
+        pool: bool,
+        /// Size and dropout of a ReLU layer before the output
+        hidden: Option<(usize, Option<f32>)>,
+        classes: usize,
+        /// Writes model files and checkpoints to a fresh directory of this name under the
+        /// system temp dir
+        output_dir: Option<&'static str>,
+    }
+
+    impl Default for TestCnn {
+        fn default() -> Self {
+            TestCnn {
+                params: test_params(),
+                data: varied_training_data(),
+                input_shape: vec![28, 28, 1],
+                conv: Some((2, (2, 2), Activation::Relu)),
+                pool: false,
+                hidden: None,
+                classes: 10,
+                output_dir: None,
+            }
+        }
+    }
+
+    impl TestCnn {
+        /// A pooled 12x12x2 network over 4 classes, sized for steps on `test_batch(6, ...)`
+        fn parallel(threads: usize) -> TestCnn {
+            TestCnn {
+                params: Hyperparameters {
+                    batch_size: 6,
+                    optimizer: OptimizerAlg::SGD(0.1),
+                    threads,
+                    ..test_params()
+                },
+                input_shape: vec![12, 12, 2],
+                conv: Some((3, (1, 1), Activation::Relu)),
+                pool: true,
+                classes: 4,
+                ..TestCnn::default()
+            }
+        }
+
+        /// Two seeded epochs with dropout
+        fn seeded(seed: u64, threads: usize) -> TestCnn {
+            TestCnn {
+                params: Hyperparameters {
+                    epochs: 2,
+                    threads,
+                    seed: Some(seed),
+                    ..test_params()
+                },
+                hidden: Some((16, Some(0.25))),
+                ..TestCnn::default()
+            }
+        }
+
+        /// A seeded two-thread run with dropout that keeps the last checkpoint in `output_dir`
+        fn checkpointed(
+            epochs: usize,
+            lr_schedule: LrSchedule,
+            output_dir: &'static str,
+        ) -> TestCnn {
+            let seeded = TestCnn::seeded(21, 2);
+            TestCnn {
+                params: Hyperparameters {
+                    epochs,
+                    optimizer: OptimizerAlg::Adam(0.01, 0.9, 0.999),
+                    lr_schedule,
+                    checkpoint_retention: Some(Retention::default()),
+                    ..seeded.params
+                },
+                output_dir: Some(output_dir),
+                ..seeded
+            }
+        }
+
+        /// A seeded run holding out a stratified fifth of the training data for validation
+        fn early_stopping(epochs: usize, early_stopping: Option<EarlyStopping>) -> TestCnn {
+            TestCnn {
+                params: Hyperparameters {
+                    epochs,
+                    seed: Some(11),
+                    early_stopping,
+                    validation_split: Some(ValidationSplit {
+                        fraction: 0.2,
+                        stratified: true,
+                    }),
+                    ..test_params()
+                },
+                ..TestCnn::default()
+            }
+        }
+
+        /// A network training on data with one NaN image, which poisons it in the first epoch.
+        /// The sigmoid passes the NaNs on where a ReLU would clamp them to zero.
+        fn diverging(divergence_guard: DivergenceGuard) -> TestCnn {
+            let mut data = mock_training_data();
+            data.trn_img[7] = TrainImage::Image(Array3::from_elem((28, 28, 1), f32::NAN));
+            TestCnn {
+                params: Hyperparameters {
+                    epochs: 2,
+                    optimizer: OptimizerAlg::SGD(0.01),
+                    seed: Some(3),
+                    divergence_guard,
+                    ..test_params()
+                },
+                data,
+                conv: Some((2, (2, 2), Activation::Sigmoid)),
+                pool: true,
+                ..TestCnn::default()
+            }
+        }
+
+        fn build(self) -> CNN {
+            let mut params = self.params;
+            if let Some(name) = self.output_dir {
+                let dir = std::env::temp_dir().join(name);
+                let _ = std::fs::remove_dir_all(&dir);
+                params.output = OutputConfig {
+                    dir,
+                    ..OutputConfig::default()
+                };
+            }
+            let mut cnn = CNN::new(self.data, params);
+            cnn.set_input_shape(self.input_shape).unwrap();
+            if let Some((filters, stride, activation)) = self.conv {
+                cnn.add_conv_layer(filters, (3, 3), stride, Padding::Valid, activation)
+                    .unwrap();
+            }
+            if self.pool {
+                cnn.add_mxpl_layer((2, 2), (2, 2)).unwrap();
+            }
+            if let Some((size, dropout)) = self.hidden {
+                cnn.add_dense_layer(size, Activation::Relu, dropout)
+                    .unwrap();
+            }
+            cnn.add_dense_layer(self.classes, Activation::Softmax, None)
+                .unwrap();
+            cnn
+        }
+
+        /// Builds the network and runs `train` on it
+        fn trained(self) -> CNN {
+            let mut cnn = self.build();
+            cnn.train().unwrap();
+            cnn
+        }
+    }
+
+    /// `n` varied images of `shape`, labelled 0 to 3 in turn
+    fn test_batch(n: usize, shape: (usize, usize, usize)) -> (Array4<f32>, Vec<usize>) {
+        let images =
+            Array4::<f32>::from_shape_fn((n, shape.0, shape.1, shape.2), |(n, x, y, c)| {
+                ((n * 17 + x * 5 + y * 3 + c) as f32).sin()
+            });
+        (images, (0..n).map(|i| i % 4).collect())
+    }
+
+    /// Kernels of the convolution `layers` start with
+    fn first_kernels(layers: &[Layer]) -> Array4<f32> {
+        match &layers[0] {
+            Layer::Conv(conv_layer) => conv_layer.kernels.clone(),
+            _ => panic!("Expected ConvLayer"),
+        }
+    }
+
+    /// One update of `TestCnn::parallel(threads)` from `layers` with `clipping`. Returns the
+    /// network, the minibatch accuracy and loss, and the gradient norm before clipping.
+    fn trained_step(
+        threads: usize,
+        clipping: GradientClipping,
+        layers: &[Layer],
+    ) -> (CNN, (f32, f32), f32) {
+        let mut cnn = TestCnn::parallel(threads).build();
+        cnn.layers = layers.to_vec();
+        cnn.gradient_clipping = clipping;
+        let (images, labels) = test_batch(6, (12, 12, 2));
+        let metrics = cnn.train_batch(images, &labels).unwrap();
+        let norm = cnn.update(labels.len());
+        (cnn, metrics, norm)
+    }
+
+    #[test]
+    fn test_cnn_parallel_accumulates_like_sequential() {
+        let layers = TestCnn::parallel(1).build().layers;
+        let (images, labels) = test_batch(6, (12, 12, 2));
+        let kernel_changes = |threads: usize| {
+            let mut cnn = TestCnn::parallel(threads).build();
+            cnn.layers = layers.clone();
+            // Two minibatches before an update add up their gradients
+            cnn.train_batch(images.clone(), &labels).unwrap();
+            cnn.train_batch(images.clone(), &labels).unwrap();
+            match &cnn.layers[0] {
+                Layer::Conv(conv_layer) => conv_layer.kernel_changes.clone(),
+                _ => panic!("Expected ConvLayer"),
+            }
+        };
+
+        let sequential = kernel_changes(1);
+        for threads in [2, 4] {
+            for (x, y) in sequential.iter().zip(kernel_changes(threads).iter()) {
+                assert!(
+                    (x - y).abs() < 1e-5,
+                    "{} != {} with {} threads",
+                    x,
+                    y,
+                    threads
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_cnn_parallel_matches_sequential() {
+        let layers = TestCnn::parallel(1).build().layers;
+        let (mut sequential, sequential_acc, _) = trained_step(1, GradientClipping::None, &layers);
+        let (mut parallel, parallel_acc, _) = trained_step(4, GradientClipping::None, &layers);
+
+        assert_eq!(sequential_acc.0, parallel_acc.0);
+        assert!((sequential_acc.1 - parallel_acc.1).abs() < 1e-5);
+        for (x, y) in first_kernels(&sequential.layers)
+            .iter()
+            .zip(first_kernels(&parallel.layers).iter())
+        {
+            assert!((x - y).abs() < 1e-5);
+        }
+
+        let (images, _) = test_batch(6, (12, 12, 2));
+        let a = sequential.forward_propagate(images.clone(), false);
+        let b = parallel.forward_propagate(images, false);
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_cnn_parallel_is_deterministic() {
+        let layers = TestCnn::parallel(1).build().layers;
+        let (mut first, _, _) = trained_step(3, GradientClipping::None, &layers);
+        let (mut second, _, _) = trained_step(3, GradientClipping::None, &layers);
+
+        let (images, _) = test_batch(6, (12, 12, 2));
+        let a = first.forward_propagate(images.clone(), false);
+        let b = second.forward_propagate(images, false);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_cnn_train_in_parallel() {
+        let params = Hyperparameters {
+            batch_size: 10,
+            epochs: 1,
+            optimizer: OptimizerAlg::SGD(0.01),
+            verbose: false,
+            threads: 3,
+            ..Hyperparameters::default()
+        };
+        let mut cnn = CNN::new(mock_training_data(), params);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_conv_layer(2, (3, 3), (2, 2), Padding::Valid, Activation::Relu)
+            .unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+
+        cnn.train().unwrap();
+
+        assert_eq!(cnn.training_history.len(), 1);
+    }
+
+    #[test]
+    fn test_cnn_predict_matches_forward() {
+        let mut cnn = TestCnn::parallel(1).build();
+        let (images, _) = test_batch(6, (12, 12, 2));
+
+        let predicted = cnn.predict(images.clone());
+        assert_eq!(predicted, cnn.forward_propagate(images, false));
+    }
+
+    #[test]
+    fn test_cnn_predict_shared_between_threads() {
+        let mut cnn = TestCnn::parallel(1).build();
+        let (images, _) = test_batch(6, (12, 12, 2));
+        let expected = cnn.forward_propagate(images.clone(), false);
+
+        let cnn = std::sync::Arc::new(cnn);
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let cnn = std::sync::Arc::clone(&cnn);
+                let images = images.clone();
+                std::thread::spawn(move || cnn.predict(images))
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+
+    fn varied_training_data() -> TrainingData {
+        let mut data = mock_training_data();
+        let image = |i: usize| {
+            TrainImage::Image(Array3::from_shape_fn((28, 28, 1), |(x, y, _)| {
+                ((i * 7 + x * 3 + y) as f32 * 0.1).sin()
+            }))
+        };
+        data.trn_img = (0..100).map(image).collect();
+        data.trn_lbl = (0..100).map(|i| i % 10).collect();
+        data.tst_img = (100..120).map(image).collect();
+        data.tst_lbl = (0..20).map(|i| i % 10).collect();
+        data
+    }
+
+    #[test]
+    fn test_cnn_seeded_runs_are_identical() {
+        for threads in [1, 2] {
+            let mut first = TestCnn::seeded(42, threads).trained();
+            let mut second = TestCnn::seeded(42, threads).trained();
+
+            assert_eq!(first.training_history, second.training_history);
+            assert_eq!(first.testing_history, second.testing_history);
+            assert_eq!(first.training_loss_history, second.training_loss_history);
+            assert_eq!(first.testing_loss_history, second.testing_loss_history);
+            let (images, _) = test_batch(3, (28, 28, 1));
+            assert_eq!(
+                first.forward_propagate(images.clone(), false),
+                second.forward_propagate(images, false)
+            );
+        }
+    }
+
+    #[test]
+    fn test_cnn_different_seeds_differ() {
+        let first = TestCnn::seeded(1, 1).trained();
+        let second = TestCnn::seeded(2, 1).trained();
+
+        assert_ne!(first_kernels(&first.layers), first_kernels(&second.layers));
+    }
+
+    #[test]
+    fn test_cnn_test_accuracy_is_exact() {
+        let mut data = mock_training_data();
+        // 7 of the 20 test images are labelled 0, out of order
+        data.tst_lbl = (0..20).map(|i| if i % 3 == 0 { 0 } else { 1 }).collect();
+        let params = Hyperparameters {
+            batch_size: 6,
+            epochs: 1,
+            optimizer: OptimizerAlg::SGD(0.01),
+            verbose: false,
+            seed: Some(0),
+            ..Hyperparameters::default()
+        };
+        let mut cnn = CNN::new(data, params);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+
+        // Zero images leave only the biases, and training on label 0 keeps class 0 on top
+        cnn.train().unwrap();
+
+        assert_eq!(cnn.testing_history, vec![7.0 / 20.0]);
+        assert_eq!(cnn.training_history, vec![1.0]);
+    }
+
+    #[test]
+    fn test_cnn_layer_before_input_shape() {
+        let mut cnn = CNN::new(mock_training_data(), Hyperparameters::default());
+
+        assert!(matches!(
+            cnn.add_dense_layer(10, Activation::Softmax, None),
+            Err(Error::InputShapeNotSet)
+        ));
+        assert!(matches!(
+            cnn.add_mxpl_layer((2, 2), (2, 2)),
+            Err(Error::InputShapeNotSet)
+        ));
+        assert!(cnn.layers.is_empty());
+    }
+
+    #[test]
+    fn test_cnn_invalid_input_shape() {
+        let mut cnn = CNN::new(mock_training_data(), Hyperparameters::default());
+
+        assert!(matches!(
+            cnn.set_input_shape(vec![]),
+            Err(Error::InvalidInputShape(_))
+        ));
+        assert!(matches!(
+            cnn.set_input_shape(vec![28, 0, 1]),
+            Err(Error::InvalidInputShape(_))
+        ));
+        assert!(matches!(
+            cnn.set_input_shape(vec![28, 28, 1, 1]),
+            Err(Error::InvalidInputShape(_))
+        ));
+        assert_eq!(cnn.input_shape, (0, 0, 0));
+    }
+
+    #[test]
+    fn test_cnn_invalid_layer_order() {
+        let mut cnn = setup_basic_cnn();
+        cnn.add_dense_layer(10, Activation::Relu, None).unwrap();
+
+        let result = cnn.add_conv_layer(4, (3, 3), (1, 1), Padding::Valid, Activation::Relu);
+        assert!(matches!(result, Err(Error::InvalidLayer(_))));
+        assert!(matches!(
+            cnn.add_global_avpl_layer(),
+            Err(Error::InvalidLayer(_))
+        ));
+        assert_eq!(cnn.layers.len(), 1);
+    }
+
+    #[test]
+    fn test_cnn_invalid_layer_sizes() {
+        let mut cnn = setup_basic_cnn();
+
+        assert!(cnn
+            .add_conv_layer(4, (29, 3), (1, 1), Padding::Valid, Activation::Relu)
+            .is_err());
+        assert!(cnn
+            .add_conv_layer(4, (3, 3), (0, 1), Padding::Valid, Activation::Relu)
+            .is_err());
+        assert!(cnn
+            .add_conv_layer(0, (3, 3), (1, 1), Padding::Valid, Activation::Relu)
+            .is_err());
+        assert!(cnn.add_mxpl_layer((2, 30), (2, 2)).is_err());
+        assert!(cnn
+            .add_dense_layer(10, Activation::Relu, Some(1.0))
+            .is_err());
+        // A kernel wider than the input fits once it is padded
+        assert!(cnn
+            .add_conv_layer(4, (29, 3), (1, 1), Padding::Explicit(1), Activation::Relu)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_cnn_train_needs_dense_output() {
+        let mut cnn = setup_basic_cnn();
+        cnn.add_conv_layer(2, (3, 3), (1, 1), Padding::Valid, Activation::Relu)
+            .unwrap();
+
+        assert!(matches!(cnn.train(), Err(Error::InvalidLayer(_))));
+        assert!(matches!(cnn.output(), Err(Error::InvalidLayer(_))));
+        assert!(matches!(
+            cnn.train_batch(create_mock_input(), &[0]),
+            Err(Error::InvalidLayer(_))
+        ));
+    }
+
+    #[test]
+    fn test_cnn_train_rejects_bad_data() {
+        let mut cnn = setup_basic_cnn();
+        cnn.verbose = false;
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+
+        cnn.minibatch_size = 0;
+        assert!(matches!(cnn.train(), Err(Error::Dataset(_))));
+        assert!(matches!(cnn.test(), Err(Error::Dataset(_))));
+        cnn.minibatch_size = 10;
+
+        // Labels have to be among the classes
+        let mut data = mock_training_data();
+        data.tst_lbl[3] = 10;
+        cnn.set_data(data);
+        assert!(matches!(cnn.train(), Err(Error::Dataset(_))));
+
+        // Images that fail to load end training with an error
+        let mut data = mock_training_data();
+        data.trn_img[3] = TrainImage::Path(PathBuf::from("tests/fixtures/missing.png"));
+        cnn.set_data(data);
+        assert!(matches!(cnn.train(), Err(Error::Dataset(_))));
+
+        // Set sizes have to match the images and labels they count
+        let mut data = mock_training_data();
+        data.trn_img.truncate(4);
+        data.trn_lbl.truncate(4);
+        data.trn_size = 8;
+        cnn.set_data(data);
+        assert!(matches!(cnn.train(), Err(Error::Dataset(_))));
+        let mut data = mock_training_data();
+        data.tst_img.pop();
+        cnn.set_data(data);
+        assert!(matches!(cnn.test(), Err(Error::Dataset(_))));
+        assert!(cnn.training_history.is_empty());
+    }
+
+    #[test]
+    fn test_cnn_train_without_test_set() {
+        let mut data = varied_training_data();
+        data.tst_img.clear();
+        data.tst_lbl.clear();
+        data.tst_size = 0;
+        let mut cnn = TestCnn {
+            data,
+            ..TestCnn::default()
+        }
+        .build();
+
+        // Test metrics are left out rather than recorded as NaN
+        cnn.train().unwrap();
+        assert_eq!(cnn.training_history.len(), 1);
+        assert!(cnn.testing_history.is_empty());
+        assert!(cnn.testing_loss_history.is_empty());
+        assert!(matches!(cnn.test(), Err(Error::Dataset(_))));
+
+        cnn.early_stopping = Some(EarlyStopping {
+            metric: Metric::TestAccuracy,
+            ..EarlyStopping::default()
+        });
+        assert!(matches!(cnn.train(), Err(Error::Dataset(_))));
+        cnn.early_stopping = None;
+        cnn.saving_strategy = SavingStrategy::BestTestingAccuracy(false);
+        assert!(matches!(cnn.train(), Err(Error::Dataset(_))));
+        assert_eq!(cnn.training_history.len(), 1);
+    }
+
+    /// A model saved by the original code, with square kernel sizes and strides saved as
+    /// single numbers and its training data inside
+    const LEGACY_MODEL: &str = "tests/fixtures/legacy_model.json";
+
+    #[test]
+    fn test_cnn_load_legacy_layers() {
+        let cnn = CNN::load(LEGACY_MODEL).unwrap();
+
+        assert_eq!(cnn.layer_order, vec!["conv", "mxpl", "dense"]);
+        match &cnn.layers[0] {
+            Layer::Conv(conv_layer) => {
+                assert_eq!(
+                    (conv_layer.kernel_size, conv_layer.stride),
+                    ((3, 3), (1, 1))
+                );
+                assert_eq!(conv_layer.padding, Padding::Valid);
+            }
+            _ => panic!("Expected ConvLayer"),
+        }
+        let mxpl = format!("{:?}", cnn.layers[1]);
+        assert!(mxpl.contains("Kernel Size: 2x2") && mxpl.contains("Stride: 2x2"));
+        let output = cnn.predict(Array4::from_elem((2, 8, 8, 1), 0.5));
+        assert_eq!(output.dim(), (2, 10));
+        assert!(output.iter().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn test_cnn_load_errors() {
+        assert!(matches!(
+            CNN::load("models/does_not_exist.json"),
+            Err(Error::Io(_))
+        ));
+        assert!(matches!(
+            CNN::load_binary("models/does_not_exist.bin"),
+            Err(Error::Io(_))
+        ));
+
+        let path = std::env::temp_dir().join("conv_nn_corrupt_model.json");
+        std::fs::write(&path, "{ not a model").unwrap();
+        assert!(matches!(
+            CNN::load(path.to_str().unwrap()),
+            Err(Error::Json(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_cnn_loss_from_hyperparameters() {
+        let params = Hyperparameters {
+            loss: Loss::MeanSquaredError,
+            ..Hyperparameters::default()
+        };
+        let mut cnn = CNN::new(mock_training_data(), params);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_dense_layer(2, Activation::Linear, None).unwrap();
+        let output = cnn.forward_propagate(Array4::<f32>::zeros((1, 28, 28, 1)), false);
+
+        // Zero input leaves the 0.01 biases, so the MSE gradient is 2 * (y - t) / 2
+        assert_eq!(cnn.loss, Loss::MeanSquaredError);
+        assert!((cnn.get_loss(&[0]).unwrap() - ((0.99f32).powi(2) + 0.0001) / 2.0).abs() < 1e-6);
+        let error = cnn.last_layer_error(&[0]).unwrap();
+        assert!((error[[0, 0]] - (output[[0, 0]] - 1.0)).abs() < 1e-6);
+        assert!((error[[0, 1]] - output[[0, 1]]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cnn_records_loss_history() {
+        let mut cnn = TestCnn::seeded(5, 1).trained();
+
+        assert_eq!(cnn.training_loss_history.len(), 2);
+        assert_eq!(cnn.testing_loss_history.len(), 2);
+        assert!(cnn
+            .training_loss_history
+            .iter()
+            .all(|loss| loss.is_finite() && *loss > 0.0));
+
+        // The recorded test loss is the mean loss over the whole test set
+        let data = varied_training_data();
+        let images = ndarray::stack(
+            ndarray::Axis(0),
+            &data
+                .tst_img
+                .iter()
+                .map(|img| match img {
+                    TrainImage::Image(img) => img.view(),
+                    TrainImage::Path(_) => unreachable!(),
+                })
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        cnn.forward_propagate(images, false);
+        let loss = cnn.get_loss(&data.tst_lbl).unwrap();
+        assert!((cnn.testing_loss_history[1] - loss).abs() < 1e-5);
+
+        let metadata = format!("{:?}", cnn);
+        assert!(metadata.contains("Training loss: ["));
+        assert!(metadata.contains("Testing loss: ["));
+    }
+
+    #[test]
+    fn test_cnn_lr_schedule_continues_after_loading() {
+        let params = Hyperparameters {
+            batch_size: 50,
+            epochs: 1,
+            optimizer: OptimizerAlg::SGD(0.1),
+            verbose: false,
+            lr_schedule: LrSchedule::StepDecay {
+                step_size: 1,
+                gamma: 0.5,
+            },
+            ..Hyperparameters::default()
+        };
+        let mut cnn = CNN::new(mock_training_data(), params);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_conv_layer(2, (3, 3), (2, 2), Padding::Valid, Activation::Relu)
+            .unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+        cnn.train().unwrap();
+        assert_eq!(cnn.scheduler.epoch, 1);
+        assert_eq!(cnn.scheduler.step, 2);
+
+        let json = serde_json::to_string(&cnn).unwrap();
+        let mut loaded: CNN = serde_json::from_str(&json).unwrap();
+        loaded.zero();
+        loaded.set_data(mock_training_data());
+        assert_eq!(loaded.scheduler, cnn.scheduler);
+
+        // The second epoch runs at half the rate, not back at the start of the schedule
+        loaded.train().unwrap();
+        assert_eq!(loaded.scheduler.epoch, 2);
+        match &loaded.layers[0] {
+            Layer::Conv(conv_layer) => assert_eq!(conv_layer.optimizer.alg.learning_rate(), 0.05),
+            _ => panic!("Expected ConvLayer"),
+        }
+    }
+
+    #[test]
+    fn test_cnn_regularization_per_layer() {
+        let mut cnn = TestCnn::parallel(1).build();
+        cnn.regularization = Regularization::l2(0.01);
+        cnn.add_dense_layer(4, Activation::Softmax, None).unwrap();
+        cnn.set_regularization(0, Regularization::l1(0.02)).unwrap();
+
+        assert!(matches!(
+            cnn.set_regularization(1, Regularization::l2(0.1)),
+            Err(Error::InvalidLayer(_))
+        ));
+        assert!(matches!(
+            cnn.set_regularization(9, Regularization::l2(0.1)),
+            Err(Error::InvalidLayer(_))
+        ));
+        match (&cnn.layers[0], &cnn.layers[2], &cnn.layers[3]) {
+            (Layer::Conv(conv), Layer::Dense(first), Layer::Dense(added)) => {
+                assert_eq!(conv.regularization, Regularization::l1(0.02));
+                assert_eq!(first.regularization, Regularization::none());
+                assert_eq!(added.regularization, Regularization::l2(0.01));
+            }
+            _ => panic!("Unexpected layers"),
+        }
+    }
+
+    #[test]
+    fn test_cnn_reported_loss_includes_regularization() {
+        let layers = TestCnn::parallel(1).build().layers;
+        let (_, (_, plain_loss), _) = trained_step(1, GradientClipping::None, &layers);
+
+        for threads in [1, 3] {
+            let mut cnn = TestCnn::parallel(threads).build();
+            cnn.layers = layers.clone();
+            cnn.set_regularization(0, Regularization::l2(0.1)).unwrap();
+            cnn.set_regularization(2, Regularization::l1(0.1)).unwrap();
+            let penalty = cnn.regularization_loss();
+            assert!(penalty > 0.0);
+
+            let (images, labels) = test_batch(6, (12, 12, 2));
+            let (_, loss) = cnn.train_batch(images, &labels).unwrap();
+            assert!((loss - plain_loss - penalty).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_cnn_clip_gradients_by_norm() {
+        let layers = TestCnn::parallel(1).build().layers;
+        let before = first_kernels(&layers);
+        let (unclipped, _, norm) = trained_step(1, GradientClipping::None, &layers);
+        let (clipped, _, clipped_norm) =
+            trained_step(1, GradientClipping::Norm(norm / 4.0), &layers);
+
+        // The reported norm is measured before clipping, and every step shrinks by the same factor
+        assert!(norm > 0.0);
+        assert_eq!(norm, clipped_norm);
+        for ((b, u), c) in before
+            .iter()
+            .zip(first_kernels(&unclipped.layers).iter())
+            .zip(first_kernels(&clipped.layers).iter())
+        {
+            assert!(((u - b) / 4.0 - (c - b)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_cnn_clip_gradients_by_value() {
+        let limit = 1e-3;
+        let layers = TestCnn::parallel(1).build().layers;
+        let before = first_kernels(&layers);
+        let (unclipped, _, _) = trained_step(1, GradientClipping::None, &layers);
+        let (clipped, _, _) = trained_step(1, GradientClipping::Value(limit), &layers);
+
+        // SGD(0.1) moves each kernel by at most lr * limit
+        assert!(before
+            .iter()
+            .zip(first_kernels(&unclipped.layers).iter())
+            .any(|(b, u)| (u - b).abs() > 0.1 * limit));
+        for (b, c) in before.iter().zip(first_kernels(&clipped.layers).iter()) {
+            assert!((c - b).abs() <= 0.1 * limit + 1e-7);
+        }
+    }
+
+    #[test]
+    fn test_cnn_records_gradient_norm_history() {
+        let cnn = TestCnn::seeded(5, 1).trained();
+
+        assert_eq!(cnn.gradient_norm_history.len(), 2);
+        assert!(cnn
+            .gradient_norm_history
+            .iter()
+            .all(|norm| norm.is_finite() && *norm > 0.0));
+        assert!(format!("{:?}", cnn).contains("Gradient norm: ["));
+    }
+
+    #[test]
+    fn test_cnn_divergence_stops_training() {
+        let mut cnn = TestCnn::diverging(DivergenceGuard::default()).build();
+
+        match cnn.train() {
+            Err(Error::NonFinite {
+                layer,
+                kind,
+                tensor,
+            }) => {
+                assert_eq!((layer, kind.as_str(), tensor), (0, "conv", "kernels"));
+            }
+            other => panic!("Expected a NonFinite error, got {:?}", other.map(|_| ())),
+        }
+        assert!(cnn.training_history.is_empty());
+        // A poisoned model is never written
+        assert!(matches!(cnn.save(false), Err(Error::NonFinite { .. })));
+    }
+
+    #[test]
+    fn test_cnn_divergence_reports_activations() {
+        let mut cnn = TestCnn::diverging(DivergenceGuard {
+            check_activations: true,
+            ..DivergenceGuard::default()
+        })
+        .build();
+
+        match cnn.train() {
+            Err(Error::NonFinite { layer, tensor, .. }) => {
+                assert_eq!((layer, tensor), (0, "output"))
+            }
+            other => panic!("Expected a NonFinite error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_cnn_divergence_rolls_back() {
+        let mut cnn = TestCnn::diverging(DivergenceGuard {
+            action: DivergenceAction::Rollback(2),
+            ..DivergenceGuard::default()
+        })
+        .build();
+        let initial_layers = format!("{:?}", cnn.layers);
+        let initial = first_kernels(&cnn.layers);
+
+        // Every epoch meets the NaN image, so the rollbacks run out and training stops
+        // with the model back at its last good state
+        assert!(matches!(cnn.train(), Err(Error::NonFinite { .. })));
+        assert!(cnn.check_finite().is_ok());
+        assert_eq!(format!("{:?}", cnn.layers), initial_layers);
+        assert_eq!(first_kernels(&cnn.layers), initial);
+    }
+
+    #[test]
+    fn test_cnn_finite_training_passes_guard() {
+        let mut cnn = TestCnn::seeded(5, 1).trained();
+
+        assert!(cnn.check_finite().is_ok());
+        cnn.divergence_guard = DivergenceGuard {
+            action: DivergenceAction::Rollback(1),
+            check_activations: true,
+        };
+        cnn.epochs = 1;
+        cnn.train().unwrap();
+        assert_eq!(cnn.training_history.len(), 3);
+    }
+
+    #[test]
+    fn test_cnn_early_stopping() {
+        // No epoch can improve the validation accuracy by more than 1, so training stops
+        // after the first epoch plus the patience
+        let policy = EarlyStopping {
+            patience: 2,
+            min_delta: 1.0,
+            restore_best: false,
+            ..EarlyStopping::default()
+        };
+        let stopped = TestCnn::early_stopping(10, Some(policy)).trained();
+        let full = TestCnn::early_stopping(3, None).trained();
+
+        assert_eq!(stopped.training_history.len(), 3);
+        assert_eq!(stopped.testing_history, full.testing_history);
+        assert_eq!(
+            format!("{:?}", stopped.layers),
+            format!("{:?}", full.layers)
+        );
+    }
+
+    #[test]
+    fn test_cnn_early_stopping_restores_best() {
+        let policy = EarlyStopping {
+            metric: Metric::TrainingLoss,
+            patience: 2,
+            min_delta: 1e6,
+            restore_best: true,
+        };
+        let stopped = TestCnn::early_stopping(10, Some(policy)).trained();
+        let best = TestCnn::early_stopping(1, None).trained();
+        let last = TestCnn::early_stopping(3, None).trained();
+
+        // Training ran three epochs but finishes on the layers of the first
+        assert_eq!(stopped.training_loss_history.len(), 3);
+        let stopped_output = stopped.predict(create_mock_input().mapv(|x| x * 0.5));
+        assert_eq!(
+            stopped_output,
+            best.predict(create_mock_input().mapv(|x| x * 0.5))
+        );
+        assert_ne!(
+            stopped_output,
+            last.predict(create_mock_input().mapv(|x| x * 0.5))
+        );
+    }
+
+    #[test]
+    fn test_cnn_validation_split() {
+        let params = Hyperparameters {
+            batch_size: 10,
+            epochs: 2,
+            optimizer: OptimizerAlg::SGD(0.05),
+            verbose: false,
+            seed: Some(2),
+            validation_split: Some(ValidationSplit {
+                fraction: 0.3,
+                stratified: false,
+            }),
+            ..Hyperparameters::default()
+        };
+        let mut cnn = CNN::new(varied_training_data(), params);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+        cnn.train().unwrap();
+
+        assert_eq!(
+            (cnn.data.trn_size, cnn.data.val_size, cnn.data.tst_size),
+            (70, 30, 20)
+        );
+        assert_eq!(cnn.validation_history.len(), 2);
+        assert_eq!(cnn.validation_loss_history.len(), 2);
+        assert!(cnn
+            .validation_loss_history
+            .iter()
+            .all(|loss| loss.is_finite()));
+        assert!(format!("{:?}", cnn).contains("Validation size: 30"));
+    }
+
+    #[test]
+    fn test_cnn_validation_metrics_need_validation_set() {
+        let params = Hyperparameters {
+            verbose: false,
+            saving_strategy: SavingStrategy::BestValidationAccuracy(false),
+            ..Hyperparameters::default()
+        };
+        let mut cnn = CNN::new(mock_training_data(), params);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+        assert!(matches!(cnn.train(), Err(Error::Dataset(_))));
+
+        cnn.saving_strategy = SavingStrategy::Never;
+        cnn.early_stopping = Some(EarlyStopping::default());
+        assert!(matches!(cnn.train(), Err(Error::Dataset(_))));
+        assert!(cnn.training_history.is_empty());
+    }
+
+    #[test]
+    fn test_cnn_explicit_validation_set() {
+        let mut data = varied_training_data();
+        data.val_img = data.tst_img.clone();
+        data.val_lbl = data.tst_lbl.clone();
+        data.val_size = data.tst_size;
+        let params = Hyperparameters {
+            batch_size: 10,
+            epochs: 1,
+            optimizer: OptimizerAlg::SGD(0.05),
+            verbose: false,
+            ..Hyperparameters::default()
+        };
+        let mut cnn = CNN::new(data, params);
+        cnn.set_input_shape(vec![28, 28, 1]).unwrap();
+        cnn.add_dense_layer(10, Activation::Softmax, None).unwrap();
+        cnn.train().unwrap();
+
+        // The same images score the same on both sets
+        assert_eq!(cnn.data.trn_size, 100);
+        assert_eq!(cnn.validation_history, cnn.testing_history);
+        assert_eq!(cnn.validation_loss_history, cnn.testing_loss_history);
+    }
+
+    fn assert_same_run(a: &mut CNN, b: &mut CNN) {
+        assert_eq!(a.training_history, b.training_history);
+        assert_eq!(a.testing_history, b.testing_history);
+        assert_eq!(a.training_loss_history, b.training_loss_history);
+        assert_eq!(a.gradient_norm_history, b.gradient_norm_history);
+        assert_eq!(a.scheduler.step, b.scheduler.step);
+        let (images, _) = test_batch(3, (28, 28, 1));
+        assert_eq!(
+            a.forward_propagate(images.clone(), false),
+            b.forward_propagate(images, false)
+        );
+    }
+
+    #[test]
+    fn test_cnn_resume_matches_uninterrupted_run() {
+        let mut full =
+            TestCnn::checkpointed(3, LrSchedule::Constant, "conv_nn_uninterrupted_run").trained();
+
+        // A huge learning rate from the second epoch on overflows the weights straight away,
+        // leaving the checkpoint of the first epoch behind
+        let exploding = LrSchedule::StepDecay {
+            step_size: 1,
+            gamma: f32::MAX,
+        };
+        let mut crashed = TestCnn::checkpointed(3, exploding, "conv_nn_crashed_run").build();
+        assert!(matches!(crashed.train(), Err(Error::NonFinite { .. })));
+        let checkpoint = &crashed.checkpoints.last().unwrap().path;
+
+        let mut resumed = CNN::load_checkpoint(checkpoint.to_str().unwrap()).unwrap();
+        resumed.set_data(varied_training_data());
+        assert_eq!(
+            resumed.progress.as_ref().map(|progress| progress.epoch),
+            Some(1)
+        );
+        assert_eq!(resumed.training_history.len(), 1);
+        resumed.scheduler.schedule = LrSchedule::Constant;
+        resumed.checkpoint_retention = None;
+        resumed.train().unwrap();
+
+        assert!(resumed.progress.is_none());
+        assert_same_run(&mut resumed, &mut full);
+        std::fs::remove_dir_all(&full.output.dir).unwrap();
+        std::fs::remove_dir_all(&crashed.output.dir).unwrap();
+    }
+
+    #[test]
+    fn test_cnn_resume_finished_run() {
+        let mut cnn =
+            TestCnn::checkpointed(2, LrSchedule::Constant, "conv_nn_finished_run").trained();
+        let checkpoint = &cnn.checkpoints.last().unwrap().path;
+
+        // The last checkpoint holds the end of the run, so there is nothing left to train
+        let mut resumed =
+            CNN::resume(checkpoint.to_str().unwrap(), varied_training_data()).unwrap();
+        assert!(resumed.progress.is_none());
+        assert_same_run(&mut resumed, &mut cnn);
+        std::fs::remove_dir_all(&cnn.output.dir).unwrap();
+    }
+
+    #[test]
+    fn test_cnn_saved_model_trains_again() {
+        let mut cnn = TestCnn::checkpointed(2, LrSchedule::Constant, "conv_nn_saved_model").build();
+        cnn.checkpoint_retention = None;
+        cnn.saving_strategy = SavingStrategy::EveryEpoch(true);
+        cnn.output.model_template = String::from("model");
+        cnn.train().unwrap();
+        let dir = cnn.output.dir.clone();
+
+        // Saved on the last epoch, while the run was still in progress, but without it
+        let file = dir.join("model.json");
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        assert!(json.get("progress").is_none());
+
+        // Loading sets up the buffers, and training runs every epoch again
+        let mut loaded = CNN::load(file.to_str().unwrap()).unwrap();
+        assert!(loaded.progress.is_none());
+        assert_eq!(loaded.training_history.len(), 2);
+        loaded.set_data(varied_training_data());
+        loaded.train().unwrap();
+        assert_eq!(loaded.training_history.len(), 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cnn_checkpoint_retention() {
+        let mut cnn =
+            TestCnn::checkpointed(5, LrSchedule::Constant, "conv_nn_checkpoint_retention").build();
+        let dir = cnn.output.dir.clone();
+        // Ties on the test accuracy go to the earliest epoch
+        cnn.checkpoint_retention = Some(Retention {
+            keep_last: 2,
+            keep_best: 1,
+            metric: Metric::TestAccuracy,
+        });
+        cnn.train().unwrap();
+
+        let best_epoch = (0..5).fold(0, |best, epoch| {
+            if cnn.testing_history[epoch] > cnn.testing_history[best] {
+                epoch
+            } else {
+                best
+            }
+        }) + 1;
+        let mut expected = vec![best_epoch, 4, 5];
+        expected.sort();
+        expected.dedup();
+        let kept: Vec<usize> = cnn
+            .checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.epoch)
+            .collect();
+        assert_eq!(kept, expected);
+
+        let mut on_disk: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        on_disk.sort();
+        let mut listed: Vec<PathBuf> = cnn
+            .checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.path.clone())
+            .collect();
+        listed.sort();
+        assert_eq!(on_disk, listed);
+        // Named by the template, with no temporary files left behind
+        assert!(listed[listed.len() - 1]
+            .to_str()
+            .unwrap()
+            .ends_with("_epoch5.json"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cnn_save_uses_output_config() {
+        let mut cnn = TestCnn::checkpointed(1, LrSchedule::Constant, "conv_nn_save_output").build();
+        let dir = cnn.output.dir.clone();
+        cnn.checkpoint_retention = None;
+        cnn.output.model_template = String::from("{name}-after-{epoch}");
+        cnn.name = String::from("digits");
+        cnn.train().unwrap();
+        cnn.save(true).unwrap();
+
+        for extension in ["json", "bin", "txt"] {
+            assert!(dir.join(format!("digits-after-1.{}", extension)).is_file());
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+        let loaded = CNN::load(dir.join("digits-after-1.json").to_str().unwrap()).unwrap();
+        assert_eq!(loaded.training_history, cnn.training_history);
+        let metadata = std::fs::read_to_string(dir.join("digits-after-1.txt")).unwrap();
+        assert!(metadata.contains("digits-after-1.json"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cnn_saved_model_leaves_out_data() {
+        let cnn = TestCnn::early_stopping(1, None).trained();
+        let json = serde_json::to_value(&cnn).unwrap();
+        assert!(json.get("data").is_none());
+
+        let mut loaded: CNN = serde_json::from_value(json).unwrap();
+        loaded.zero();
+        assert_eq!(loaded.data.trn_size, 0);
+        assert!(matches!(loaded.train(), Err(Error::Dataset(_))));
+
+        // The same validation samples are held out when the data is supplied again
+        loaded.set_data(varied_training_data());
+        assert_eq!(loaded.data.trn_size, cnn.data.trn_size);
+        assert_eq!(loaded.data.val_lbl, cnn.data.val_lbl);
+        assert_eq!(loaded.data.trn_lbl, cnn.data.trn_lbl);
+        loaded.train().unwrap();
+    }
+
+    #[test]
+    fn test_cnn_loaded_model_needs_data_to_test() {
+        let mut cnn = TestCnn::checkpointed(1, LrSchedule::Constant, "conv_nn_load_test").build();
+        let dir = cnn.output.dir.clone();
+        cnn.checkpoint_retention = None;
+        cnn.output.model_template = String::from("{name}");
+        cnn.name = String::from("digits");
+        cnn.train().unwrap();
+        cnn.save(true).unwrap();
+
+        let mut loaded = CNN::load(dir.join("digits.json").to_str().unwrap()).unwrap();
+        match loaded.test() {
+            Err(Error::Dataset(message)) => assert!(message.contains("set_data")),
+            other => panic!("Expected a dataset error, got {:?}", other),
+        }
+        loaded.set_data(varied_training_data());
+        loaded.test().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cnn_load_legacy() {
+        // Plain loading drops the data saved inside the model
+        let loaded = CNN::load(LEGACY_MODEL).unwrap();
+        assert_eq!(loaded.data.trn_size, 0);
+
+        let mut migrated = CNN::load_legacy(LEGACY_MODEL).unwrap();
+        assert_eq!((migrated.data.trn_size, migrated.data.tst_size), (4, 2));
+        assert_eq!(migrated.data.trn_lbl, vec![0, 1, 2, 3]);
+        assert_eq!(
+            format!("{:?}", migrated.layers),
+            format!("{:?}", loaded.layers)
+        );
+        assert_eq!(migrated.training_history.len(), 1);
+
+        // The migrated model carries on training on its own data and saves without it
+        migrated.verbose = false;
+        migrated.train().unwrap();
+        assert_eq!(migrated.training_history.len(), 2);
+        assert!(serde_json::to_value(&migrated)
+            .unwrap()
+            .get("data")
+            .is_none());
+    }
 }
@@ -1,73 +1,119 @@
 #[cfg(test)]
-mod tests {    
-    use conv_nn::conv_layers::ConvLayer;
+mod tests {
+    use conv_nn::activation::Activation;
+    use conv_nn::conv_layers::{ConvLayer, Padding};
     use conv_nn::optimizer::OptimizerAlg;
-    use ndarray::Array3;
+    use conv_nn::regularization::Regularization;
+    use ndarray::{Array1, Array4};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
 
     #[test]
     fn test_conv_layer_initialization() {
         let input_size = (28, 28, 3);
-        let kernel_size = 3;
-        let stride = 1;
+        let kernel_size = (3, 3);
+        let stride = (1, 1);
         let num_filters = 5;
         let optimizer_alg = OptimizerAlg::Adam(0.9, 0.999, 1e-8);
 
-        let conv_layer = ConvLayer::new(input_size, kernel_size, stride, num_filters, optimizer_alg);
+        let conv_layer = ConvLayer::new(
+            input_size,
+            kernel_size,
+            stride,
+            Padding::Valid,
+            num_filters,
+            Activation::Relu,
+            optimizer_alg,
+        );
 
         assert_eq!(conv_layer.input_size, input_size);
         assert_eq!(conv_layer.kernel_size, kernel_size);
         assert_eq!(conv_layer.stride, stride);
         assert_eq!(conv_layer.num_filters, num_filters);
-        assert_eq!(conv_layer.output_size, ((input_size.0 - kernel_size) / stride + 1, (input_size.1 - kernel_size) / stride + 1, num_filters));
+        assert_eq!(
+            conv_layer.output_size,
+            (
+                (input_size.0 - kernel_size.0) / stride.0 + 1,
+                (input_size.1 - kernel_size.1) / stride.1 + 1,
+                num_filters
+            )
+        );
     }
 
     #[test]
     fn test_conv_layer_forward_propagation() {
         let input_size = (5, 5, 3);
-        let kernel_size = 3;
-        let stride = 1;
+        let kernel_size = (3, 3);
+        let stride = (1, 1);
         let num_filters = 2;
         let optimizer_alg = OptimizerAlg::Adam(0.9, 0.999, 1e-8);
 
-        let mut conv_layer = ConvLayer::new(input_size, kernel_size, stride, num_filters, optimizer_alg);
-        let input = Array3::<f32>::ones(input_size);
+        let mut conv_layer = ConvLayer::new(
+            input_size,
+            kernel_size,
+            stride,
+            Padding::Valid,
+            num_filters,
+            Activation::Relu,
+            optimizer_alg,
+        );
+        let input = Array4::<f32>::ones(batch_shape(2, input_size));
 
         let output = conv_layer.forward_propagate(input.clone());
 
-        assert_eq!(output.shape(), &[3, 3, num_filters]); // Output size calculation
+        assert_eq!(output.shape(), &[2, 3, 3, num_filters]); // Output size calculation
     }
 
     #[test]
     fn test_conv_layer_backward_propagation() {
         let input_size = (5, 5, 3);
-        let kernel_size = 3;
-        let stride = 1;
+        let kernel_size = (3, 3);
+        let stride = (1, 1);
         let num_filters = 2;
         let optimizer_alg = OptimizerAlg::Adam(0.9, 0.999, 1e-8);
 
-        let mut conv_layer = ConvLayer::new(input_size, kernel_size, stride, num_filters, optimizer_alg);
-        let input = Array3::<f32>::ones(input_size);
+        let mut conv_layer = ConvLayer::new(
+            input_size,
+            kernel_size,
+            stride,
+            Padding::Valid,
+            num_filters,
+            Activation::Relu,
+            optimizer_alg,
+        );
+        let input = Array4::<f32>::ones(batch_shape(2, input_size));
         conv_layer.forward_propagate(input);
 
-        let error = Array3::<f32>::ones(conv_layer.output_size);
+        let error = Array4::<f32>::ones(batch_shape(2, conv_layer.output_size));
         let backprop_error = conv_layer.back_propagate(error);
 
-        assert_eq!(backprop_error.shape(), &[5, 5, 3]); // Should match input size
+        assert_eq!(backprop_error.shape(), &[2, 5, 5, 3]); // Should match input size
     }
 
     #[test]
     fn test_conv_layer_update() {
         let input_size = (5, 5, 3);
-        let kernel_size = 3;
-        let stride = 1;
+        let kernel_size = (3, 3);
+        let stride = (1, 1);
         let num_filters = 2;
         let optimizer_alg = OptimizerAlg::Adam(0.9, 0.999, 1e-8);
 
-        let mut conv_layer = ConvLayer::new(input_size, kernel_size, stride, num_filters, optimizer_alg);
-        let input = Array3::<f32>::ones(input_size);
+        // A fixed seed, since kernels that are all negative would leave nothing to update
+        let mut rng = ChaCha8Rng::seed_from_u64(3);
+        let mut conv_layer = ConvLayer::new_with_rng(
+            input_size,
+            kernel_size,
+            stride,
+            Padding::Valid,
+            num_filters,
+            Activation::Relu,
+            optimizer_alg,
+            &mut rng,
+        );
+        let input = Array4::<f32>::ones(batch_shape(1, input_size));
         conv_layer.forward_propagate(input);
 
-        let error = Array3::<f32>::ones(conv_layer.output_size);
+        let error = Array4::<f32>::ones(batch_shape(1, conv_layer.output_size));
         conv_layer.back_propagate(error);
 
         let before_update = conv_layer.kernels.clone();
@@ -79,15 +125,501 @@ mod tests {
     #[test]
     fn test_conv_layer_zero_reset() {
         let input_size = (5, 5, 3);
-        let kernel_size = 3;
-        let stride = 1;
+        let kernel_size = (3, 3);
+        let stride = (1, 1);
         let num_filters = 2;
         let optimizer_alg = OptimizerAlg::Adam(0.9, 0.999, 1e-8);
 
-        let mut conv_layer = ConvLayer::new(input_size, kernel_size, stride, num_filters, optimizer_alg);
+        let mut conv_layer = ConvLayer::new(
+            input_size,
+            kernel_size,
+            stride,
+            Padding::Valid,
+            num_filters,
+            Activation::Relu,
+            optimizer_alg,
+        );
         conv_layer.zero();
 
         assert!(conv_layer.kernel_changes.iter().all(|&x| x == 0.0));
         assert!(conv_layer.output.iter().all(|&x| x == 0.0));
     }
-}
\ No newline at end of file
+
+    fn batch_shape(batch_size: usize, size: (usize, usize, usize)) -> (usize, usize, usize, usize) {
+        (batch_size, size.0, size.1, size.2)
+    }
+
+    /// Central difference of the loss `sum(output * weights)`, taken elementwise on the outputs
+    /// before summing to keep f32 cancellation error out of the comparison
+    fn loss_difference(
+        plus: Array4<f32>,
+        minus: Array4<f32>,
+        weights: &Array4<f32>,
+        eps: f32,
+    ) -> f32 {
+        ((plus - minus) * weights).sum() / (2.0 * eps)
+    }
+
+    /// Compares the analytic input and kernel gradients against central finite differences
+    /// for the weighted-sum loss `sum(output * weights)`.
+    fn check_gradients(
+        input_size: (usize, usize, usize),
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+        padding: Padding,
+        activation: Activation,
+    ) {
+        let optimizer_alg = OptimizerAlg::SGD(0.1);
+        let mut conv_layer = ConvLayer::new(
+            input_size,
+            kernel_size,
+            stride,
+            padding,
+            2,
+            activation,
+            optimizer_alg,
+        );
+        // Fixed kernels and inputs keep the pre-activations away from the ReLU kink
+        conv_layer.kernels =
+            Array4::<f32>::from_shape_fn(conv_layer.kernels.dim(), |(f, ky, kx, c)| {
+                0.3 * ((f * 13 + ky * 5 + kx * 3 + c * 7) as f32 + 0.5).sin()
+            });
+        conv_layer.biases =
+            Array1::<f32>::from_shape_fn(conv_layer.num_filters, |f| 0.1 * (f as f32 + 1.0));
+        // A batch of two distinct samples checks that gradients accumulate across the batch
+        let input = Array4::<f32>::from_shape_fn(batch_shape(2, input_size), |(n, x, y, c)| {
+            ((n * 19 + x * 7 + y * 3 + c * 5) as f32).sin()
+        });
+        let weights =
+            Array4::<f32>::from_shape_fn(batch_shape(2, conv_layer.output_size), |(n, x, y, f)| {
+                ((n * 13 + x * 5 + y * 11 + f) as f32).cos()
+            });
+
+        conv_layer.forward_propagate(input.clone());
+        let input_grad = conv_layer.back_propagate(weights.clone());
+        assert_eq!(input_grad.dim(), batch_shape(2, input_size));
+
+        let eps = 1e-3;
+        for idx in ndarray::indices(input.dim()) {
+            let (mut plus, mut minus) = (input.clone(), input.clone());
+            plus[idx] += eps;
+            minus[idx] -= eps;
+            let numeric = loss_difference(
+                conv_layer.forward_propagate(plus),
+                conv_layer.forward_propagate(minus),
+                &weights,
+                eps,
+            );
+            assert!(
+                (numeric - input_grad[idx]).abs() < 1e-2,
+                "input gradient mismatch at {:?}: {} vs {}",
+                idx,
+                numeric,
+                input_grad[idx]
+            );
+        }
+
+        // kernel_changes accumulates the negative gradient
+        let kernel_grad = -conv_layer.kernel_changes.clone();
+        for idx in ndarray::indices(conv_layer.kernels.dim()) {
+            let original = conv_layer.kernels[idx];
+            conv_layer.kernels[idx] = original + eps;
+            let plus = conv_layer.forward_propagate(input.clone());
+            conv_layer.kernels[idx] = original - eps;
+            let minus = conv_layer.forward_propagate(input.clone());
+            conv_layer.kernels[idx] = original;
+            let numeric = loss_difference(plus, minus, &weights, eps);
+            assert!(
+                (numeric - kernel_grad[idx]).abs() < 1e-2,
+                "kernel gradient mismatch at {:?}: {} vs {}",
+                idx,
+                numeric,
+                kernel_grad[idx]
+            );
+        }
+
+        let bias_grad = -conv_layer.bias_changes.clone();
+        for f in 0..conv_layer.num_filters {
+            let original = conv_layer.biases[f];
+            conv_layer.biases[f] = original + eps;
+            let plus = conv_layer.forward_propagate(input.clone());
+            conv_layer.biases[f] = original - eps;
+            let minus = conv_layer.forward_propagate(input.clone());
+            conv_layer.biases[f] = original;
+            let numeric = loss_difference(plus, minus, &weights, eps);
+            assert!(
+                (numeric - bias_grad[f]).abs() < 1e-2,
+                "bias gradient mismatch at {}: {} vs {}",
+                f,
+                numeric,
+                bias_grad[f]
+            );
+        }
+    }
+
+    #[test]
+    fn test_conv_layer_output_size_with_padding_and_stride() {
+        let optimizer_alg = OptimizerAlg::SGD(0.1);
+        let same = ConvLayer::new(
+            (28, 28, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Same,
+            4,
+            Activation::Relu,
+            optimizer_alg,
+        );
+        assert_eq!(same.output_size, (28, 28, 4));
+
+        let same_strided = ConvLayer::new(
+            (28, 28, 1),
+            (3, 3),
+            (2, 2),
+            Padding::Same,
+            4,
+            Activation::Relu,
+            optimizer_alg,
+        );
+        assert_eq!(same_strided.output_size, (14, 14, 4));
+
+        let explicit = ConvLayer::new(
+            (28, 28, 1),
+            (5, 5),
+            (1, 1),
+            Padding::Explicit(1),
+            4,
+            Activation::Relu,
+            optimizer_alg,
+        );
+        assert_eq!(explicit.output_size, (26, 26, 4));
+
+        let valid_strided = ConvLayer::new(
+            (7, 7, 1),
+            (3, 3),
+            (2, 2),
+            Padding::Valid,
+            4,
+            Activation::Relu,
+            optimizer_alg,
+        );
+        assert_eq!(valid_strided.output_size, (3, 3, 4));
+    }
+
+    #[test]
+    fn test_conv_layer_gradients_valid() {
+        check_gradients((6, 6, 2), (3, 3), (1, 1), Padding::Valid, Activation::Relu);
+        check_gradients((7, 7, 2), (3, 3), (2, 2), Padding::Valid, Activation::Relu);
+    }
+
+    #[test]
+    fn test_conv_layer_gradients_same() {
+        check_gradients((6, 6, 2), (3, 3), (1, 1), Padding::Same, Activation::Relu);
+        check_gradients((6, 6, 2), (2, 2), (2, 2), Padding::Same, Activation::Relu);
+        check_gradients((7, 7, 1), (4, 4), (3, 3), Padding::Same, Activation::Relu);
+    }
+
+    #[test]
+    fn test_conv_layer_gradients_explicit() {
+        check_gradients(
+            (5, 5, 2),
+            (3, 3),
+            (1, 1),
+            Padding::Explicit(1),
+            Activation::Relu,
+        );
+        check_gradients(
+            (5, 5, 2),
+            (3, 3),
+            (2, 2),
+            Padding::Explicit(2),
+            Activation::Relu,
+        );
+    }
+
+    #[test]
+    fn test_conv_layer_non_square_output_size() {
+        let optimizer_alg = OptimizerAlg::SGD(0.1);
+        let conv_layer = ConvLayer::new(
+            (32, 64, 3),
+            (3, 5),
+            (1, 2),
+            Padding::Valid,
+            4,
+            Activation::Relu,
+            optimizer_alg,
+        );
+        assert_eq!(conv_layer.output_size, (30, 30, 4));
+        assert_eq!(conv_layer.kernels.dim(), (4, 3, 5, 3));
+
+        let same = ConvLayer::new(
+            (32, 64, 3),
+            (3, 5),
+            (2, 1),
+            Padding::Same,
+            4,
+            Activation::Relu,
+            optimizer_alg,
+        );
+        assert_eq!(same.output_size, (16, 64, 4));
+    }
+
+    #[test]
+    fn test_conv_layer_gradients_non_square() {
+        check_gradients((5, 8, 2), (3, 2), (1, 1), Padding::Valid, Activation::Relu);
+        check_gradients((6, 9, 1), (2, 4), (2, 3), Padding::Same, Activation::Relu);
+        check_gradients(
+            (4, 7, 2),
+            (3, 3),
+            (1, 2),
+            Padding::Explicit(1),
+            Activation::Sigmoid,
+        );
+    }
+
+    /// Runs the im2col and naive paths on the same layer and input and checks they agree
+    fn check_im2col_matches_naive(
+        input_size: (usize, usize, usize),
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
+        padding: Padding,
+    ) {
+        let mut conv_layer = ConvLayer::new(
+            input_size,
+            kernel_size,
+            stride,
+            padding,
+            3,
+            Activation::Sigmoid,
+            OptimizerAlg::SGD(0.1),
+        );
+        conv_layer.biases =
+            Array1::<f32>::from_shape_fn(conv_layer.num_filters, |f| 0.1 * f as f32);
+        let input = Array4::<f32>::from_shape_fn(batch_shape(3, input_size), |(n, x, y, c)| {
+            ((n * 19 + x * 7 + y * 3 + c * 5) as f32).sin()
+        });
+        let error =
+            Array4::<f32>::from_shape_fn(batch_shape(3, conv_layer.output_size), |(n, x, y, f)| {
+                ((n * 13 + x * 5 + y * 11 + f) as f32).cos()
+            });
+
+        let output = conv_layer.forward_propagate(input.clone());
+        let prev_error = conv_layer.back_propagate(error.clone());
+        let (kernel_changes, bias_changes) = (
+            conv_layer.kernel_changes.clone(),
+            conv_layer.bias_changes.clone(),
+        );
+        conv_layer.zero();
+
+        let naive_output = conv_layer.forward_propagate_naive(input);
+        let naive_prev_error = conv_layer.back_propagate_naive(error);
+
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        assert!(output
+            .iter()
+            .zip(naive_output.iter())
+            .all(|(&a, &b)| close(a, b)));
+        assert!(prev_error
+            .iter()
+            .zip(naive_prev_error.iter())
+            .all(|(&a, &b)| close(a, b)));
+        assert!(kernel_changes
+            .iter()
+            .zip(conv_layer.kernel_changes.iter())
+            .all(|(&a, &b)| close(a, b)));
+        assert!(bias_changes
+            .iter()
+            .zip(conv_layer.bias_changes.iter())
+            .all(|(&a, &b)| close(a, b)));
+    }
+
+    #[test]
+    fn test_conv_layer_im2col_matches_naive() {
+        check_im2col_matches_naive((6, 6, 2), (3, 3), (1, 1), Padding::Valid);
+        check_im2col_matches_naive((7, 7, 3), (3, 3), (2, 2), Padding::Valid);
+        check_im2col_matches_naive((6, 6, 2), (3, 3), (1, 1), Padding::Same);
+        check_im2col_matches_naive((9, 5, 1), (4, 2), (3, 2), Padding::Same);
+        check_im2col_matches_naive((5, 8, 2), (3, 3), (2, 1), Padding::Explicit(2));
+        check_im2col_matches_naive((4, 4, 1), (4, 4), (1, 1), Padding::Valid);
+    }
+
+    #[test]
+    fn test_conv_layer_gradients_sigmoid() {
+        check_gradients(
+            (6, 6, 2),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            Activation::Sigmoid,
+        );
+        check_gradients(
+            (6, 6, 2),
+            (3, 3),
+            (2, 2),
+            Padding::Same,
+            Activation::Sigmoid,
+        );
+    }
+
+    #[test]
+    fn test_conv_layer_gradients_linear() {
+        check_gradients(
+            (6, 6, 2),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            Activation::Linear,
+        );
+        check_gradients(
+            (5, 5, 2),
+            (3, 3),
+            (2, 2),
+            Padding::Explicit(1),
+            Activation::Linear,
+        );
+    }
+
+    #[test]
+    fn test_conv_layer_linear_activation_keeps_negatives() {
+        let mut conv_layer = ConvLayer::new(
+            (3, 3, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            1,
+            Activation::Linear,
+            OptimizerAlg::SGD(0.1),
+        );
+        conv_layer.kernels.fill(-1.0);
+
+        let output = conv_layer.forward_propagate(Array4::<f32>::ones((1, 3, 3, 1)));
+
+        assert_eq!(output[[0, 0, 0, 0]], -9.0);
+    }
+
+    #[test]
+    fn test_conv_layer_bias_update() {
+        let mut conv_layer = ConvLayer::new(
+            (5, 5, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            2,
+            Activation::Relu,
+            OptimizerAlg::SGD(0.1),
+        );
+        conv_layer.forward_propagate(Array4::<f32>::ones((1, 5, 5, 1)));
+        conv_layer.back_propagate(Array4::<f32>::ones(batch_shape(1, conv_layer.output_size)));
+
+        let expected = &conv_layer.biases + &(&conv_layer.bias_changes * 0.1);
+        conv_layer.update(1);
+
+        assert_eq!(conv_layer.biases, expected);
+        assert!(conv_layer.bias_changes.iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_conv_layer_loads_without_biases() {
+        let conv_layer = ConvLayer::new(
+            (5, 5, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            4,
+            Activation::Relu,
+            OptimizerAlg::SGD(0.1),
+        );
+        let mut json = serde_json::to_value(&conv_layer).unwrap();
+        json.as_object_mut().unwrap().remove("biases");
+
+        let mut loaded: ConvLayer = serde_json::from_value(json).unwrap();
+        loaded.zero();
+
+        assert_eq!(loaded.biases, Array1::<f32>::zeros(4));
+        assert_eq!(loaded.kernels, conv_layer.kernels);
+    }
+
+    #[test]
+    fn test_conv_layer_infer_matches_forward() {
+        let mut conv_layer = ConvLayer::new(
+            (6, 5, 2),
+            (3, 3),
+            (2, 1),
+            Padding::Same,
+            3,
+            Activation::Relu,
+            OptimizerAlg::SGD(0.1),
+        );
+        let input = Array4::<f32>::from_shape_fn((2, 6, 5, 2), |(n, x, y, c)| {
+            ((n * 13 + x * 7 + y * 3 + c) as f32).cos()
+        });
+
+        let inferred = conv_layer.infer(input.clone());
+        assert_eq!(conv_layer.output.dim().0, 0);
+
+        assert_eq!(inferred, conv_layer.forward_propagate(input));
+    }
+
+    #[test]
+    fn test_conv_layer_regularization_skips_biases() {
+        let input_size = (5, 5, 3);
+        let optimizer_alg = OptimizerAlg::SGD(0.1);
+
+        let mut decayed = ConvLayer::new(
+            input_size,
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            2,
+            Activation::Relu,
+            optimizer_alg,
+        );
+        decayed.regularization = Regularization::weight_decay(0.5);
+        let mut penalized = decayed.clone();
+        penalized.regularization = Regularization::l2(0.5);
+
+        let kernels = decayed.kernels.clone();
+        let biases = decayed.biases.clone();
+        let penalty = 0.25 * kernels.mapv(|k| k * k).sum();
+        assert!((penalized.regularization_loss() - penalty).abs() < 1e-4);
+        assert_eq!(decayed.regularization_loss(), 0.0);
+        decayed.update(1);
+        penalized.update(1);
+
+        // Without any gradient both shrink the kernels by lr * 0.5 and leave the biases alone
+        let expected = &kernels * 0.95;
+        for layer in [&decayed, &penalized] {
+            assert_eq!(layer.biases, biases);
+            for (k, e) in layer.kernels.iter().zip(expected.iter()) {
+                assert!((k - e).abs() < 1e-6);
+            }
+        }
+    }
+    #[test]
+    fn test_conv_layer_replica_leaves_out_optimizer_state() {
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        let mut conv_layer = ConvLayer::new_with_rng(
+            (5, 5, 3),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            2,
+            Activation::Relu,
+            OptimizerAlg::Adam(0.9, 0.999, 1e-8),
+            &mut rng,
+        );
+        let mut replica = conv_layer.replica();
+        assert_eq!(replica.kernels, conv_layer.kernels);
+        assert_eq!(replica.optimizer.momentum1.len(), 0);
+        assert_eq!(replica.optimizer.momentum2.len(), 0);
+
+        // Reloading after an update picks up the new parameters and still has no moments
+        conv_layer.forward_propagate(Array4::<f32>::ones(batch_shape(1, (5, 5, 3))));
+        conv_layer.back_propagate(Array4::<f32>::ones(batch_shape(1, conv_layer.output_size)));
+        conv_layer.update(1);
+        assert_ne!(replica.kernels, conv_layer.kernels);
+        replica.load_parameters(&conv_layer);
+        assert_eq!(replica.kernels, conv_layer.kernels);
+        assert_eq!(replica.biases, conv_layer.biases);
+        assert_eq!(replica.optimizer.momentum1.len(), 0);
+    }
+}
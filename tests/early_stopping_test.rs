@@ -0,0 +1,81 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::early_stopping::{EarlyStopper, EarlyStopping, EpochMetrics, Metric};
+
+    /// Feeds `values` to a stopper, returning the epoch it stops after, if any
+    fn stop_epoch(policy: EarlyStopping, values: &[f32]) -> Option<usize> {
+        let mut stopper = EarlyStopper::new(policy);
+        for (epoch, &value) in values.iter().enumerate() {
+            stopper.record(epoch, value);
+            if stopper.should_stop() {
+                return Some(epoch);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_metric_value() {
+        let mut metrics = EpochMetrics {
+            train_acc: 0.1,
+            train_loss: 0.2,
+            validation: Some((0.5, 0.6)),
+            test: Some((0.3, 0.4)),
+        };
+
+        assert_eq!(Metric::ValidationAccuracy.value(&metrics), Some(0.5));
+        assert_eq!(Metric::ValidationLoss.value(&metrics), Some(0.6));
+        assert_eq!(Metric::TestAccuracy.value(&metrics), Some(0.3));
+        assert_eq!(Metric::TestLoss.value(&metrics), Some(0.4));
+        assert_eq!(Metric::TrainingAccuracy.value(&metrics), Some(0.1));
+        assert_eq!(Metric::TrainingLoss.value(&metrics), Some(0.2));
+        metrics.validation = None;
+        assert_eq!(Metric::ValidationAccuracy.value(&metrics), None);
+        metrics.test = None;
+        assert_eq!(Metric::TestLoss.value(&metrics), None);
+    }
+
+    #[test]
+    fn test_accuracy_patience() {
+        let policy = EarlyStopping {
+            patience: 2,
+            ..EarlyStopping::default()
+        };
+
+        assert_eq!(stop_epoch(policy, &[0.5, 0.6, 0.7, 0.8]), None);
+        assert_eq!(stop_epoch(policy, &[0.5, 0.6, 0.6, 0.55, 0.9]), Some(3));
+        // Improving resets the patience
+        assert_eq!(stop_epoch(policy, &[0.5, 0.4, 0.6, 0.5, 0.5]), Some(4));
+    }
+
+    #[test]
+    fn test_loss_is_minimised() {
+        let policy = EarlyStopping {
+            metric: Metric::TestLoss,
+            patience: 1,
+            ..EarlyStopping::default()
+        };
+
+        assert_eq!(stop_epoch(policy, &[2.0, 1.0, 0.5]), None);
+        assert_eq!(stop_epoch(policy, &[2.0, 1.0, 1.5]), Some(2));
+    }
+
+    #[test]
+    fn test_min_delta() {
+        let policy = EarlyStopping {
+            patience: 2,
+            min_delta: 0.05,
+            ..EarlyStopping::default()
+        };
+        let mut stopper = EarlyStopper::new(policy);
+
+        assert!(stopper.record(0, 0.5));
+        // Too small to count as an improvement
+        assert!(!stopper.record(1, 0.52));
+        assert!(stopper.record(2, 0.6));
+        assert!(!stopper.record(3, 0.64));
+        assert!(!stopper.record(4, 0.62));
+        assert!(stopper.should_stop());
+        assert_eq!((stopper.best, stopper.best_epoch), (Some(0.6), 2));
+    }
+}
@@ -0,0 +1,121 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::activation::{forward_batch, Activation};
+    use conv_nn::loss::Loss;
+    use ndarray::{array, Array2};
+
+    fn logits() -> Array2<f32> {
+        // Kept away from zero so ReLU stays differentiable
+        array![[0.6, -1.2, 1.5, 0.3], [-0.4, 0.9, -1.6, 2.1]]
+    }
+
+    /// Compares the logits gradient with central differences of the mean batch loss
+    fn check_output_error(loss: Loss, activation: Activation) {
+        let labels = [2, 1];
+        let z = logits();
+        let output = forward_batch(z.clone(), activation);
+        let analytic = loss.output_error(&output, &labels, activation);
+
+        let eps = 1e-2;
+        for n in 0..z.nrows() {
+            for i in 0..z.ncols() {
+                let mut plus = z.clone();
+                plus[[n, i]] += eps;
+                let mut minus = z.clone();
+                minus[[n, i]] -= eps;
+                let difference = loss.loss(&forward_batch(plus, activation), &labels)
+                    - loss.loss(&forward_batch(minus, activation), &labels);
+                // The loss is averaged over the batch, the error is per sample
+                let numeric = difference / (2.0 * eps) * labels.len() as f32;
+
+                assert!(
+                    (numeric - analytic[[n, i]]).abs() < 1e-2 * (1.0 + numeric.abs()),
+                    "{:?} with {:?} at {:?}: numeric {} vs analytic {}",
+                    loss,
+                    activation,
+                    (n, i),
+                    numeric,
+                    analytic[[n, i]]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_cross_entropy_gradients() {
+        check_output_error(Loss::CrossEntropy, Activation::Softmax);
+        check_output_error(Loss::CrossEntropy, Activation::Sigmoid);
+    }
+
+    #[test]
+    fn test_mean_squared_error_gradients() {
+        for activation in [
+            Activation::Linear,
+            Activation::Relu,
+            Activation::Sigmoid,
+            Activation::Softmax,
+        ] {
+            check_output_error(Loss::MeanSquaredError, activation);
+        }
+    }
+
+    #[test]
+    fn test_binary_cross_entropy_gradients() {
+        check_output_error(Loss::BinaryCrossEntropy, Activation::Sigmoid);
+        check_output_error(Loss::BinaryCrossEntropy, Activation::Softmax);
+    }
+
+    #[test]
+    fn test_label_smoothing_gradients() {
+        check_output_error(Loss::LabelSmoothing(0.1), Activation::Softmax);
+        check_output_error(Loss::LabelSmoothing(0.1), Activation::Sigmoid);
+    }
+
+    #[test]
+    fn test_focal_gradients() {
+        check_output_error(Loss::Focal(2.0), Activation::Softmax);
+        check_output_error(Loss::Focal(0.5), Activation::Sigmoid);
+    }
+
+    #[test]
+    fn test_cross_entropy_value() {
+        let output = array![[0.25, 0.5, 0.25], [0.1, 0.1, 0.8]];
+        let loss = Loss::CrossEntropy.loss(&output, &[1, 2]);
+
+        assert!((loss - (-(0.5f32.ln()) - 0.8f32.ln()) / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mean_squared_error_value() {
+        let output = array![[0.5, 0.5]];
+        let loss = Loss::MeanSquaredError.loss(&output, &[0]);
+
+        assert!((loss - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_label_smoothing_targets() {
+        let targets = Loss::LabelSmoothing(0.2).targets(&[0], 4);
+
+        assert_eq!(targets, array![[0.85, 0.05, 0.05, 0.05]]);
+    }
+
+    #[test]
+    fn test_focal_without_focus_is_cross_entropy() {
+        let output = array![[0.2, 0.7, 0.1]];
+
+        assert_eq!(
+            Loss::Focal(0.0).loss(&output, &[1]),
+            Loss::CrossEntropy.loss(&output, &[1])
+        );
+    }
+
+    #[test]
+    fn test_softmax_cross_entropy_saturated_output() {
+        // The fused gradient stays finite even when the labelled output underflows
+        let output = array![[1.0, 0.0]];
+        let error = Loss::CrossEntropy.output_error(&output, &[1], Activation::Softmax);
+
+        assert_eq!(error, array![[1.0, -1.0]]);
+    }
+}
@@ -0,0 +1,222 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::error::Error;
+    use conv_nn::mnist_impl::*;
+    use conv_nn::utils::{TrainImage, TrainingData, ValidationSplit};
+    use ndarray::Array3;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    fn indexed_training_data(size: usize) -> TrainingData {
+        // Every image is filled with its own index so batches can be traced back
+        let images: Vec<TrainImage> = (0..size)
+            .map(|i| TrainImage::Image(Array3::from_elem((2, 2, 1), i as f32)))
+            .collect();
+        TrainingData {
+            trn_img: images.clone(),
+            trn_lbl: (0..size).collect(),
+            tst_img: images,
+            tst_lbl: (0..size).collect(),
+            rows: 2,
+            cols: 2,
+            trn_size: size,
+            tst_size: size,
+            classes: (0..size).map(|i| (i, i)).collect(),
+            ..TrainingData::default()
+        }
+    }
+
+    #[test]
+    fn test_epoch_batches_visit_every_sample_once() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let batches = epoch_batches(103, 10, &mut rng);
+
+        assert_eq!(batches.len(), 11);
+        assert_eq!(batches.last().unwrap().len(), 3);
+        let mut visited: Vec<usize> = batches.concat();
+        assert_ne!(visited, (0..103).collect::<Vec<usize>>());
+        visited.sort();
+        assert_eq!(visited, (0..103).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_epoch_batches_reshuffle_every_epoch() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let first = epoch_batches(50, 10, &mut rng);
+        let second = epoch_batches(50, 10, &mut rng);
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_ordered_batches() {
+        let batches = ordered_batches(7, 3);
+
+        assert_eq!(batches, vec![vec![0, 1, 2], vec![3, 4, 5], vec![6]]);
+    }
+
+    #[test]
+    fn test_get_batch() {
+        let data = indexed_training_data(5);
+        let (images, labels) = get_batch(&data, &[4, 1]).unwrap();
+
+        assert_eq!(images.dim(), (2, 2, 2, 1));
+        assert_eq!(labels, vec![4, 1]);
+        assert_eq!(images[[0, 1, 1, 0]], 4.0);
+        assert_eq!(images[[1, 0, 0, 0]], 1.0);
+    }
+
+    #[test]
+    fn test_get_batch_out_of_range() {
+        let mut data = indexed_training_data(5);
+        assert!(matches!(get_batch(&data, &[5]), Err(Error::Dataset(_))));
+        data.tst_lbl.pop();
+        assert!(matches!(
+            get_test_batch(&data, &[4]),
+            Err(Error::Dataset(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_mnist_missing_files() {
+        let result = load_mnist(std::env::temp_dir().join("conv_nn_no_mnist_here"));
+
+        assert!(matches!(result, Err(Error::Dataset(_))));
+    }
+
+    /// An IDX header with the given magic number and dimensions
+    fn idx_header(magic: u32, dims: &[u32]) -> Vec<u8> {
+        std::iter::once(magic)
+            .chain(dims.iter().copied())
+            .flat_map(u32::to_be_bytes)
+            .collect()
+    }
+
+    #[test]
+    fn test_load_mnist_malformed_files() {
+        let dir = std::env::temp_dir().join("conv_nn_malformed_mnist");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [
+            "train-images-idx3-ubyte",
+            "train-labels-idx1-ubyte",
+            "t10k-images-idx3-ubyte",
+            "t10k-labels-idx1-ubyte",
+        ];
+        let malformed = |contents: Vec<u8>| {
+            for file in files {
+                std::fs::write(dir.join(file), &contents).unwrap();
+            }
+            match load_mnist(&dir) {
+                Err(Error::Dataset(message)) => message,
+                _ => panic!("Expected a dataset error"),
+            }
+        };
+
+        assert!(malformed(vec![0, 0, 8]).contains("too short"));
+        assert!(malformed(idx_header(2049, &[60000, 28, 28])).contains("magic number"));
+        assert!(malformed(idx_header(2051, &[5, 28, 28])).contains("dimensions"));
+        let mut truncated = idx_header(2051, &[60000, 28, 28]);
+        truncated.extend([0; 784]);
+        assert!(malformed(truncated).contains("bytes"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Index each training image was filled with
+    fn image_indices(images: &[TrainImage]) -> Vec<usize> {
+        images
+            .iter()
+            .map(|img| match img {
+                TrainImage::Image(img) => img[[0, 0, 0]] as usize,
+                TrainImage::Path(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split_validation() {
+        let mut data = indexed_training_data(50);
+        let mut rng = ChaCha8Rng::seed_from_u64(4);
+        split_validation(
+            &mut data,
+            ValidationSplit {
+                fraction: 0.2,
+                stratified: false,
+            },
+            &mut rng,
+        );
+
+        assert_eq!((data.trn_size, data.val_size, data.tst_size), (40, 10, 50));
+        assert_eq!(image_indices(&data.trn_img), data.trn_lbl);
+        assert_eq!(image_indices(&data.val_img), data.val_lbl);
+        // Every sample ends up in exactly one of the two sets
+        let mut all = [data.trn_lbl.clone(), data.val_lbl.clone()].concat();
+        all.sort();
+        assert_eq!(all, (0..50).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_split_validation_stratified() {
+        let mut data = indexed_training_data(60);
+        // Two thirds of the samples are label 0, the rest label 1
+        data.trn_lbl = (0..60).map(|i| (i % 3 == 2) as usize).collect();
+        let mut rng = ChaCha8Rng::seed_from_u64(4);
+        split_validation(
+            &mut data,
+            ValidationSplit {
+                fraction: 0.25,
+                stratified: true,
+            },
+            &mut rng,
+        );
+
+        let count = |labels: &[usize], label: usize| labels.iter().filter(|&&l| l == label).count();
+        assert_eq!((count(&data.val_lbl, 0), count(&data.val_lbl, 1)), (10, 5));
+        assert_eq!((count(&data.trn_lbl, 0), count(&data.trn_lbl, 1)), (30, 15));
+    }
+
+    #[test]
+    fn test_split_validation_ignores_stale_size() {
+        for stratified in [false, true] {
+            let mut data = indexed_training_data(20);
+            data.trn_lbl = (0..20).map(|i| i % 2).collect();
+            data.trn_size = 5;
+            let mut rng = ChaCha8Rng::seed_from_u64(2);
+            split_validation(
+                &mut data,
+                ValidationSplit {
+                    fraction: 0.5,
+                    stratified,
+                },
+                &mut rng,
+            );
+
+            assert_eq!((data.trn_size, data.val_size), (10, 10));
+            assert_eq!(
+                image_indices(&data.val_img)
+                    .iter()
+                    .map(|i| i % 2)
+                    .collect::<Vec<_>>(),
+                data.val_lbl
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_validation_batch() {
+        let mut data = indexed_training_data(10);
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        split_validation(
+            &mut data,
+            ValidationSplit {
+                fraction: 0.5,
+                stratified: false,
+            },
+            &mut rng,
+        );
+        let (images, labels) = get_validation_batch(&data, &[4, 0]).unwrap();
+
+        assert_eq!(labels, vec![data.val_lbl[4], data.val_lbl[0]]);
+        assert_eq!(images[[0, 0, 0, 0]] as usize, data.val_lbl[4]);
+    }
+}
@@ -0,0 +1,98 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::mxpl::MxplLayer;
+    use ndarray::Array4;
+
+    #[test]
+    fn test_mxpl_layer_non_square_output_size() {
+        let mxpl_layer = MxplLayer::new((32, 64, 3), (2, 4), (2, 4));
+        assert_eq!(mxpl_layer.output_size, (16, 16, 3));
+
+        let overlapping = MxplLayer::new((9, 6, 1), (3, 2), (2, 1));
+        assert_eq!(overlapping.output_size, (4, 5, 1));
+    }
+
+    #[test]
+    fn test_mxpl_layer_forward_propagation() {
+        let mut mxpl_layer = MxplLayer::new((2, 4, 1), (2, 2), (2, 2));
+        let input = Array4::<f32>::from_shape_fn((1, 2, 4, 1), |(_, x, y, _)| (x * 4 + y) as f32);
+
+        let output = mxpl_layer.forward_propagate(input);
+
+        assert_eq!(output.shape(), &[1, 1, 2, 1]);
+        assert_eq!(output[[0, 0, 0, 0]], 5.0);
+        assert_eq!(output[[0, 0, 1, 0]], 7.0);
+    }
+
+    #[test]
+    fn test_mxpl_layer_backward_propagation() {
+        let mut mxpl_layer = MxplLayer::new((2, 4, 1), (2, 2), (2, 2));
+        let input = Array4::<f32>::from_shape_fn((1, 2, 4, 1), |(_, x, y, _)| (x * 4 + y) as f32);
+        mxpl_layer.forward_propagate(input);
+
+        let error = Array4::<f32>::from_elem((1, 1, 2, 1), 3.0);
+        let prev_error = mxpl_layer.back_propagate(error);
+
+        assert_eq!(prev_error.shape(), &[1, 2, 4, 1]);
+        assert_eq!(prev_error[[0, 1, 1, 0]], 3.0);
+        assert_eq!(prev_error[[0, 1, 3, 0]], 3.0);
+        assert_eq!(prev_error.sum(), 6.0);
+    }
+
+    #[test]
+    fn test_mxpl_layer_negative_inputs() {
+        let mut mxpl_layer = MxplLayer::new((2, 2, 1), (2, 2), (2, 2));
+        let input =
+            Array4::<f32>::from_shape_fn((1, 2, 2, 1), |(_, x, y, _)| -((x * 2 + y) as f32) - 2.0);
+
+        let output = mxpl_layer.forward_propagate(input);
+        let prev_error = mxpl_layer.back_propagate(Array4::<f32>::ones((1, 1, 1, 1)));
+
+        assert_eq!(output[[0, 0, 0, 0]], -2.0);
+        assert_eq!(prev_error[[0, 0, 0, 0]], 1.0);
+    }
+
+    #[test]
+    fn test_mxpl_layer_overlapping_windows_accumulate() {
+        let mut mxpl_layer = MxplLayer::new((3, 1, 1), (2, 1), (1, 1));
+        let input = Array4::<f32>::from_shape_vec((1, 3, 1, 1), vec![0.0, 5.0, 1.0]).unwrap();
+        mxpl_layer.forward_propagate(input);
+
+        let prev_error = mxpl_layer.back_propagate(Array4::<f32>::ones((1, 2, 1, 1)));
+
+        assert_eq!(prev_error[[0, 1, 0, 0]], 2.0);
+    }
+
+    #[test]
+    fn test_mxpl_layer_batch_samples_are_independent() {
+        let mut mxpl_layer = MxplLayer::new((2, 2, 1), (2, 2), (2, 2));
+        let input = Array4::<f32>::from_shape_fn((2, 2, 2, 1), |(n, x, y, _)| {
+            if n == 0 {
+                (x * 2 + y) as f32
+            } else {
+                -((x * 2 + y) as f32)
+            }
+        });
+
+        let output = mxpl_layer.forward_propagate(input);
+        let prev_error = mxpl_layer.back_propagate(Array4::<f32>::ones((2, 1, 1, 1)));
+
+        assert_eq!(output[[0, 0, 0, 0]], 3.0);
+        assert_eq!(output[[1, 0, 0, 0]], 0.0);
+        assert_eq!(prev_error[[0, 1, 1, 0]], 1.0);
+        assert_eq!(prev_error[[1, 0, 0, 0]], 1.0);
+    }
+
+    #[test]
+    fn test_mxpl_layer_infer_matches_forward() {
+        let mut mxpl_layer = MxplLayer::new((4, 4, 2), (2, 2), (2, 2));
+        let input = Array4::<f32>::from_shape_fn((2, 4, 4, 2), |(n, x, y, c)| {
+            ((n * 5 + x * 3 + y + c) as f32).sin()
+        });
+
+        assert_eq!(
+            mxpl_layer.infer(input.clone()),
+            mxpl_layer.forward_propagate(input)
+        );
+    }
+}
@@ -0,0 +1,390 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::activation::Activation;
+    use conv_nn::conv_layers::{ConvLayer, Padding};
+    use conv_nn::optimizer::{Optimizer, Optimizer1D, Optimizer2D, Optimizer4D, OptimizerAlg};
+    use ndarray::{array, Array1, Array3, Array4};
+
+    /// Changes for two updates of the parameters [1, 2] with gradients [1, -2] then [0.5, 1]
+    fn two_steps(alg: OptimizerAlg) -> (Array1<f32>, Array1<f32>) {
+        let mut optimizer = Optimizer1D::new(alg, 2);
+        let params = array![1.0, 2.0];
+        (
+            optimizer.weight_changes(&params, &array![1.0, -2.0]),
+            optimizer.weight_changes(&params, &array![0.5, 1.0]),
+        )
+    }
+
+    /// Bias changes of a conv layer with biases [1, 2] over two updates with bias gradients [1, -2] then [0.5, 1]
+    fn layer_bias_steps(alg: OptimizerAlg) -> (Array1<f32>, Array1<f32>) {
+        let mut conv_layer = ConvLayer::new(
+            (3, 3, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            2,
+            Activation::Linear,
+            alg,
+        );
+        conv_layer.biases = array![1.0, 2.0];
+        let mut step = |gradients: Array1<f32>| {
+            let before = conv_layer.biases.clone();
+            conv_layer.bias_changes = gradients;
+            conv_layer.update(1);
+            &conv_layer.biases - &before
+        };
+        let first = step(array![1.0, -2.0]);
+        (first, step(array![0.5, 1.0]))
+    }
+
+    fn assert_close(a: &Array1<f32>, b: &Array1<f32>) {
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-6, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_sgd() {
+        let (first, second) = two_steps(OptimizerAlg::SGD(0.1));
+
+        assert_close(&first, &array![0.1, -0.2]);
+        assert_close(&second, &array![0.05, 0.1]);
+    }
+
+    #[test]
+    fn test_momentum() {
+        let (first, second) = two_steps(OptimizerAlg::Momentum(0.1, 0.9));
+
+        // v1 = g1, v2 = 0.9 * g1 + g2
+        assert_close(&first, &array![0.1, -0.2]);
+        assert_close(&second, &array![0.14, -0.08]);
+    }
+
+    #[test]
+    fn test_nesterov() {
+        let (first, second) = two_steps(OptimizerAlg::Nesterov(0.1, 0.9));
+
+        // Steps along g + 0.9 * v, with v1 = g1 and v2 = [1.4, -0.8]
+        assert_close(&first, &array![0.19, -0.38]);
+        assert_close(&second, &array![0.176, 0.028]);
+    }
+
+    #[test]
+    fn test_rmsprop() {
+        let (first, second) = two_steps(OptimizerAlg::RMSProp(0.1, 0.9));
+
+        // s1 = 0.1 * g1^2 = [0.1, 0.4], s2 = 0.9 * s1 + 0.1 * g2^2 = [0.115, 0.46]
+        assert_close(&first, &array![0.1 / 0.1f32.sqrt(), -0.2 / 0.4f32.sqrt()]);
+        assert_close(
+            &second,
+            &array![0.05 / 0.115f32.sqrt(), 0.1 / 0.46f32.sqrt()],
+        );
+    }
+
+    #[test]
+    fn test_adagrad() {
+        let (first, second) = two_steps(OptimizerAlg::AdaGrad(0.1));
+
+        // G1 = [1, 4], G2 = [1.25, 5]
+        assert_close(&first, &array![0.1, -0.1]);
+        assert_close(&second, &array![0.05 / 1.25f32.sqrt(), 0.1 / 5.0f32.sqrt()]);
+    }
+
+    #[test]
+    fn test_adadelta() {
+        let (first, second) = two_steps(OptimizerAlg::AdaDelta(1.0, 0.9));
+
+        // The first step is g * sqrt(eps) / sqrt(0.1 * g^2 + eps), after which the squared
+        // updates are about 1e-6 and the squared gradients [0.115, 0.46]
+        assert_close(&first, &array![0.0031623, -0.0031623]);
+        assert_close(&second, &array![0.0020851, 0.0020851]);
+    }
+
+    #[test]
+    fn test_adam() {
+        let (first, second) = two_steps(OptimizerAlg::Adam(0.01, 0.9, 0.999));
+
+        // The first bias-corrected step is lr * sign(g)
+        assert_close(&first, &array![0.01, -0.01]);
+        // m2 = [0.14, -0.08] / 0.19, v2 = [0.001249, 0.004996] / 0.001999
+        let m = array![0.14f32, -0.08] / 0.19;
+        let v = array![0.001249f32, 0.004996] / 0.001999;
+        assert_close(&second, &(m * 0.01 / v.mapv(f32::sqrt)));
+        assert_close(&second, &array![0.0093218, -0.0026634]);
+    }
+
+    #[test]
+    fn test_adamw() {
+        let (first, _) = two_steps(OptimizerAlg::AdamW(0.01, 0.9, 0.999, 0.1));
+
+        // The Adam step [1, -1] minus 0.1 times the parameters [1, 2]
+        assert_close(&first, &array![0.009, -0.012]);
+    }
+
+    #[test]
+    fn test_lamb() {
+        let (first, _) = two_steps(OptimizerAlg::LAMB(0.01, 0.9, 0.999, 0.1));
+
+        // The AdamW step [0.9, -1.2] scaled by |params| / |step| = sqrt(5) / 1.5
+        let trust_ratio = 5.0f32.sqrt() / 1.5;
+        assert_close(&first, &(array![0.009, -0.012] * trust_ratio));
+    }
+
+    #[test]
+    fn test_lamb_zero_params() {
+        let mut optimizer = Optimizer1D::new(OptimizerAlg::LAMB(0.01, 0.9, 0.999, 0.1), 2);
+        let changes = optimizer.weight_changes(&Array1::zeros(2), &array![1.0, -2.0]);
+
+        // Without a parameter norm the trust ratio falls back to 1
+        assert_close(&changes, &array![0.01, -0.01]);
+    }
+
+    #[test]
+    fn test_same_algorithm_for_every_dimension() {
+        let mut flat = Optimizer1D::new(OptimizerAlg::Adam(0.01, 0.9, 0.999), 8);
+        let mut cube = Optimizer::new(OptimizerAlg::Adam(0.01, 0.9, 0.999), (2, 2, 2));
+        let mut kernels = Optimizer4D::new(OptimizerAlg::Adam(0.01, 0.9, 0.999), (2, 1, 2, 2));
+
+        let gradients = Array3::<f32>::from_shape_fn((2, 2, 2), |(i, j, k)| {
+            i as f32 - j as f32 + 0.5 * k as f32
+        });
+        let params = Array3::<f32>::ones((2, 2, 2));
+        for _ in 0..3 {
+            let flat_changes = flat.weight_changes(
+                &params.clone().into_shape_with_order(8).unwrap(),
+                &gradients.clone().into_shape_with_order(8).unwrap(),
+            );
+            let cube_changes = cube.weight_changes(&params, &gradients);
+            let kernel_changes = kernels.weight_changes(
+                &params.clone().into_shape_with_order((2, 1, 2, 2)).unwrap(),
+                &gradients
+                    .clone()
+                    .into_shape_with_order((2, 1, 2, 2))
+                    .unwrap(),
+            );
+
+            assert_eq!(flat_changes, cube_changes.into_shape_with_order(8).unwrap());
+            assert_eq!(
+                flat_changes,
+                kernel_changes.into_shape_with_order(8).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_learning_rate() {
+        let algs = [
+            OptimizerAlg::AdaGrad(0.1),
+            OptimizerAlg::AdaDelta(0.1, 0.95),
+            OptimizerAlg::Nesterov(0.1, 0.9),
+            OptimizerAlg::AdamW(0.1, 0.9, 0.999, 0.01),
+            OptimizerAlg::LAMB(0.1, 0.9, 0.999, 0.01),
+        ];
+        for alg in algs {
+            assert_eq!(alg.learning_rate(), 0.1);
+            assert_eq!(alg.with_learning_rate(0.5).learning_rate(), 0.5);
+        }
+        assert_eq!(
+            OptimizerAlg::AdamW(0.1, 0.9, 0.999, 0.01).without_weight_decay(),
+            OptimizerAlg::AdamW(0.1, 0.9, 0.999, 0.0)
+        );
+        assert_eq!(
+            OptimizerAlg::Nesterov(0.1, 0.9).without_weight_decay(),
+            OptimizerAlg::Nesterov(0.1, 0.9)
+        );
+    }
+
+    #[test]
+    fn test_bias_state_is_separate_from_weights() {
+        let mut conv_layer = ConvLayer::new(
+            (3, 3, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            2,
+            Activation::Linear,
+            OptimizerAlg::Momentum(0.1, 0.9),
+        );
+        conv_layer.kernel_changes = Array4::ones(conv_layer.kernels.dim());
+        conv_layer.bias_changes = array![1.0, -2.0];
+        conv_layer.update(1);
+        conv_layer.bias_changes = array![0.5, 1.0];
+        conv_layer.update(1);
+
+        // The biases follow the momentum reference of their own gradients
+        assert_close(&conv_layer.biases, &array![0.24, -0.28]);
+        assert_close(&conv_layer.bias_optimizer.momentum1, &array![1.4, -0.8]);
+    }
+
+    #[test]
+    fn test_sgd_bias_changes() {
+        let (first, second) = layer_bias_steps(OptimizerAlg::SGD(0.1));
+
+        assert_close(&first, &array![0.1, -0.2]);
+        assert_close(&second, &array![0.05, 0.1]);
+    }
+
+    #[test]
+    fn test_momentum_bias_changes() {
+        let (first, second) = layer_bias_steps(OptimizerAlg::Momentum(0.1, 0.9));
+
+        assert_close(&first, &array![0.1, -0.2]);
+        assert_close(&second, &array![0.14, -0.08]);
+    }
+
+    #[test]
+    fn test_nesterov_bias_changes() {
+        let (first, second) = layer_bias_steps(OptimizerAlg::Nesterov(0.1, 0.9));
+
+        assert_close(&first, &array![0.19, -0.38]);
+        assert_close(&second, &array![0.176, 0.028]);
+    }
+
+    #[test]
+    fn test_rmsprop_bias_changes() {
+        let (first, second) = layer_bias_steps(OptimizerAlg::RMSProp(0.1, 0.9));
+
+        assert_close(&first, &array![0.1 / 0.1f32.sqrt(), -0.2 / 0.4f32.sqrt()]);
+        assert_close(
+            &second,
+            &array![0.05 / 0.115f32.sqrt(), 0.1 / 0.46f32.sqrt()],
+        );
+    }
+
+    #[test]
+    fn test_adagrad_bias_changes() {
+        let (first, second) = layer_bias_steps(OptimizerAlg::AdaGrad(0.1));
+
+        assert_close(&first, &array![0.1, -0.1]);
+        assert_close(&second, &array![0.05 / 1.25f32.sqrt(), 0.1 / 5.0f32.sqrt()]);
+    }
+
+    #[test]
+    fn test_adadelta_bias_changes() {
+        let (first, second) = layer_bias_steps(OptimizerAlg::AdaDelta(1.0, 0.9));
+
+        assert_close(&first, &array![0.0031623, -0.0031623]);
+        assert_close(&second, &array![0.0020851, 0.0020851]);
+    }
+
+    #[test]
+    fn test_adam_bias_changes() {
+        let (first, second) = layer_bias_steps(OptimizerAlg::Adam(0.01, 0.9, 0.999));
+
+        assert_close(&first, &array![0.01, -0.01]);
+        assert_close(&second, &array![0.0093218, -0.0026634]);
+    }
+
+    #[test]
+    fn test_adamw_bias_changes() {
+        let (first, second) = layer_bias_steps(OptimizerAlg::AdamW(0.01, 0.9, 0.999, 0.1));
+
+        // Without weight decay the biases take the Adam steps
+        assert_close(&first, &array![0.01, -0.01]);
+        assert_close(&second, &array![0.0093218, -0.0026634]);
+    }
+
+    #[test]
+    fn test_lamb_bias_changes() {
+        let (first, second) = layer_bias_steps(OptimizerAlg::LAMB(0.01, 0.9, 0.999, 0.1));
+
+        // The Adam steps scaled by |biases| / |step|, where the biases are [1, 2] and then
+        // [1.0158114, 1.9841886] after the first update
+        assert_close(
+            &first,
+            &(array![0.01, -0.01] * (5.0f32.sqrt() / 2.0f32.sqrt())),
+        );
+        assert_close(&second, &array![0.0214333, -0.0061238]);
+    }
+
+    #[test]
+    fn test_bias_follows_learning_rate_changes() {
+        let mut conv_layer = ConvLayer::new(
+            (3, 3, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            2,
+            Activation::Linear,
+            OptimizerAlg::SGD(0.1),
+        );
+        conv_layer.set_learning_rate(1.0);
+        conv_layer.bias_changes = array![1.0, -2.0];
+        conv_layer.update(1);
+
+        assert_eq!(conv_layer.bias_optimizer.alg, OptimizerAlg::SGD(1.0));
+        assert_close(&conv_layer.biases, &array![1.0, -2.0]);
+    }
+
+    #[test]
+    fn test_biases_skip_built_in_weight_decay() {
+        let conv_layer = ConvLayer::new(
+            (3, 3, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            2,
+            Activation::Linear,
+            OptimizerAlg::AdamW(0.01, 0.9, 0.999, 0.1),
+        );
+
+        assert_eq!(
+            conv_layer.optimizer.alg,
+            OptimizerAlg::AdamW(0.01, 0.9, 0.999, 0.1)
+        );
+        assert_eq!(
+            conv_layer.bias_optimizer.alg,
+            OptimizerAlg::AdamW(0.01, 0.9, 0.999, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_old_models_get_bias_state() {
+        let conv_layer = ConvLayer::new(
+            (3, 3, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            2,
+            Activation::Linear,
+            OptimizerAlg::Momentum(0.1, 0.9),
+        );
+        let mut json: serde_json::Value = serde_json::to_value(&conv_layer).unwrap();
+        json.as_object_mut().unwrap().remove("bias_optimizer");
+
+        let mut loaded: ConvLayer = serde_json::from_value(json).unwrap();
+        loaded.zero();
+
+        assert_eq!(loaded.bias_optimizer.alg, OptimizerAlg::Momentum(0.1, 0.9));
+        assert_eq!(loaded.bias_optimizer.momentum1, Array1::<f32>::zeros(2));
+    }
+
+    #[test]
+    fn test_default_bias_state_is_resized() {
+        let mut conv_layer = ConvLayer::new(
+            (3, 3, 1),
+            (3, 3),
+            (1, 1),
+            Padding::Valid,
+            2,
+            Activation::Linear,
+            OptimizerAlg::Adam(0.01, 0.9, 0.999),
+        );
+        conv_layer.bias_optimizer = Optimizer1D::new(OptimizerAlg::Adam(0.01, 0.9, 0.999), 0);
+        conv_layer.zero();
+
+        assert_eq!(conv_layer.bias_optimizer.momentum1, Array1::<f32>::zeros(2));
+        assert_eq!(conv_layer.bias_optimizer.momentum2, Array1::<f32>::zeros(2));
+        conv_layer.bias_changes = array![1.0, -2.0];
+        conv_layer.update(1);
+        assert_close(&conv_layer.biases, &array![0.01, -0.01]);
+    }
+
+    #[test]
+    fn test_dense_optimizer_shape() {
+        let optimizer = Optimizer2D::new(OptimizerAlg::SGD(0.1), (2, 3));
+
+        assert_eq!(optimizer.momentum1.dim(), (2, 3));
+        assert_eq!(optimizer.momentum2.dim(), (2, 3));
+    }
+}
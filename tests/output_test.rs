@@ -0,0 +1,129 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::early_stopping::Metric;
+    use conv_nn::error::Error;
+    use conv_nn::output::{write_atomically, Checkpoint, OutputConfig, Retention};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    /// Checkpoints for epochs 1, 2, ... scoring `metrics`
+    fn checkpoints(metrics: &[Option<f32>]) -> Vec<Checkpoint> {
+        metrics
+            .iter()
+            .enumerate()
+            .map(|(i, &metric)| Checkpoint {
+                path: PathBuf::from(format!("epoch{}.json", i + 1)),
+                epoch: i + 1,
+                metric,
+            })
+            .collect()
+    }
+
+    fn epochs(checkpoints: &[Checkpoint]) -> Vec<usize> {
+        checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.epoch)
+            .collect()
+    }
+
+    #[test]
+    fn test_output_paths() {
+        let output = OutputConfig::default();
+
+        assert_eq!(
+            output.model_path("mnist", 17, 3, "bin"),
+            PathBuf::from("models/mnist_17.bin")
+        );
+        assert_eq!(
+            output.checkpoint_path("mnist", 17, 3),
+            PathBuf::from("models/mnist_17_epoch3.json")
+        );
+
+        let output = OutputConfig {
+            dir: PathBuf::from("runs/a"),
+            model_template: String::from("{epoch}-{name}-{epoch}"),
+            ..OutputConfig::default()
+        };
+        assert_eq!(
+            output.model_path("mnist", 17, 3, "txt"),
+            PathBuf::from("runs/a/3-mnist-3.txt")
+        );
+    }
+
+    #[test]
+    fn test_retention_keep_last() {
+        let retention = Retention {
+            keep_last: 2,
+            ..Retention::default()
+        };
+        let mut kept = checkpoints(&[None, None, None, None]);
+        let removed = retention.prune(&mut kept);
+
+        assert_eq!(epochs(&kept), vec![3, 4]);
+        assert_eq!(epochs(&removed), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_retention_keep_best() {
+        let retention = Retention {
+            keep_last: 1,
+            keep_best: 2,
+            metric: Metric::ValidationAccuracy,
+        };
+        let mut kept = checkpoints(&[Some(0.5), Some(0.9), None, Some(0.7), Some(0.9), Some(0.1)]);
+        retention.prune(&mut kept);
+
+        // Ties go to the earlier checkpoint
+        assert_eq!(epochs(&kept), vec![2, 5, 6]);
+
+        let retention = Retention {
+            keep_last: 1,
+            keep_best: 1,
+            metric: Metric::ValidationLoss,
+        };
+        let mut kept = checkpoints(&[Some(0.5), Some(f32::NAN), Some(0.2), Some(0.4)]);
+        retention.prune(&mut kept);
+
+        assert_eq!(epochs(&kept), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_retention_keeps_latest() {
+        let retention = Retention {
+            keep_last: 0,
+            keep_best: 0,
+            metric: Metric::TrainingLoss,
+        };
+        let mut kept = checkpoints(&[Some(0.1), Some(0.2)]);
+        retention.prune(&mut kept);
+
+        assert_eq!(epochs(&kept), vec![2]);
+        assert!(!retention.needs_validation());
+        assert!(Retention {
+            keep_best: 1,
+            ..Retention::default()
+        }
+        .needs_validation());
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = std::env::temp_dir().join("conv_nn_write_atomically");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("nested").join("file.txt");
+
+        write_atomically(&path, |file| Ok(write!(file, "first")?)).unwrap();
+        write_atomically(&path, |file| Ok(write!(file, "second")?)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+
+        // A failed write leaves the old file in place and no temporary file behind
+        let failed = write_atomically(&path, |file| {
+            write!(file, "partial")?;
+            Err(Error::Dataset(String::from("failed")))
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(std::fs::read_dir(dir.join("nested")).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::regularization::Regularization;
+    use ndarray::{array, Array2};
+
+    #[test]
+    fn test_no_regularization() {
+        let weights = array![[1.0, -2.0], [0.5, 0.0]];
+        let mut changes = Array2::<f32>::ones((2, 2));
+
+        let regularization = Regularization::none();
+        regularization.add_gradients(&weights, &mut changes);
+
+        assert_eq!(regularization.penalty(&weights), 0.0);
+        assert_eq!(changes, Array2::<f32>::ones((2, 2)));
+        assert!(regularization.decay(&weights, 0.1).is_none());
+    }
+
+    #[test]
+    fn test_l1_penalty() {
+        let weights = array![[1.0, -2.0], [0.5, 0.0]];
+        let mut changes = Array2::<f32>::zeros((2, 2));
+
+        let regularization = Regularization::l1(0.1);
+        regularization.add_gradients(&weights, &mut changes);
+
+        assert!((regularization.penalty(&weights) - 0.35).abs() < 1e-6);
+        assert_eq!(changes, array![[-0.1, 0.1], [-0.1, 0.0]]);
+    }
+
+    #[test]
+    fn test_l2_penalty() {
+        let weights = array![[1.0, -2.0], [0.5, 0.0]];
+        let mut changes = Array2::<f32>::zeros((2, 2));
+
+        let regularization = Regularization::l2(0.1);
+        regularization.add_gradients(&weights, &mut changes);
+
+        // 0.1 / 2 * (1 + 4 + 0.25)
+        assert!((regularization.penalty(&weights) - 0.2625).abs() < 1e-6);
+        assert_eq!(changes, array![[-0.1, 0.2], [-0.05, 0.0]]);
+    }
+
+    #[test]
+    fn test_weight_decay() {
+        let weights = array![[1.0, -2.0], [0.5, 0.0]];
+        let mut changes = Array2::<f32>::zeros((2, 2));
+
+        let regularization = Regularization::weight_decay(0.5);
+        regularization.add_gradients(&weights, &mut changes);
+
+        // Decoupled decay neither adds to the loss nor goes through the gradients
+        assert_eq!(regularization.penalty(&weights), 0.0);
+        assert_eq!(changes, Array2::<f32>::zeros((2, 2)));
+        assert_eq!(
+            regularization.decay(&weights, 0.1).unwrap(),
+            array![[-0.05, 0.1], [-0.025, 0.0]]
+        );
+    }
+}
@@ -0,0 +1,118 @@
+#[cfg(test)]
+mod tests {
+    use conv_nn::scheduler::{LrSchedule, LrScheduler};
+
+    /// Learning rate at the start of every epoch, `epochs` epochs of `steps` minibatches
+    fn rates_per_epoch(
+        scheduler: &mut LrScheduler,
+        epochs: usize,
+        steps: usize,
+        accuracies: &[f32],
+    ) -> Vec<f32> {
+        let mut rates = vec![];
+        for epoch in 0..epochs {
+            rates.push(scheduler.learning_rate(1.0, steps));
+            for _ in 0..steps {
+                scheduler.end_step();
+            }
+            scheduler.end_epoch(Some(accuracies.get(epoch).copied().unwrap_or(0.0)));
+        }
+        rates
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-6, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_constant() {
+        let mut scheduler = LrScheduler::default();
+
+        assert_close(
+            &rates_per_epoch(&mut scheduler, 3, 2, &[]),
+            &[1.0, 1.0, 1.0],
+        );
+    }
+
+    #[test]
+    fn test_step_decay() {
+        let mut scheduler = LrScheduler::new(LrSchedule::StepDecay {
+            step_size: 2,
+            gamma: 0.5,
+        });
+
+        assert_close(
+            &rates_per_epoch(&mut scheduler, 5, 3, &[]),
+            &[1.0, 1.0, 0.5, 0.5, 0.25],
+        );
+    }
+
+    #[test]
+    fn test_exponential() {
+        let mut scheduler = LrScheduler::new(LrSchedule::Exponential { gamma: 0.9 });
+
+        assert_close(
+            &rates_per_epoch(&mut scheduler, 3, 1, &[]),
+            &[1.0, 0.9, 0.81],
+        );
+    }
+
+    #[test]
+    fn test_cosine_warm_restarts() {
+        let mut scheduler = LrScheduler::new(LrSchedule::CosineWarmRestarts {
+            period: 2,
+            mult: 2,
+            min_lr: 0.0,
+        });
+
+        // Cycles of 2 then 4 epochs, restarting at the top each time
+        assert_close(
+            &rates_per_epoch(&mut scheduler, 7, 1, &[]),
+            &[1.0, 0.5, 1.0, 0.853_553_4, 0.5, 0.146_446_6, 1.0],
+        );
+    }
+
+    #[test]
+    fn test_cosine_within_an_epoch() {
+        let mut scheduler = LrScheduler::new(LrSchedule::CosineWarmRestarts {
+            period: 1,
+            mult: 1,
+            min_lr: 0.1,
+        });
+        scheduler.end_step();
+
+        assert!((scheduler.learning_rate(1.0, 2) - 0.55).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_linear_warmup() {
+        let mut scheduler = LrScheduler::new(LrSchedule::LinearWarmup { warmup_steps: 4 });
+        let mut rates = vec![];
+        for _ in 0..6 {
+            rates.push(scheduler.learning_rate(1.0, 10));
+            scheduler.end_step();
+        }
+
+        assert_close(&rates, &[0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_reduce_on_plateau() {
+        let mut scheduler = LrScheduler::new(LrSchedule::ReduceOnPlateau {
+            factor: 0.5,
+            patience: 1,
+            min_lr: 0.2,
+        });
+        let accuracies = [0.5, 0.6, 0.6, 0.55, 0.7, 0.6, 0.6, 0.6, 0.6];
+
+        // Reduced after a second epoch without improvement, and never below min_lr
+        assert_close(
+            &rates_per_epoch(&mut scheduler, 9, 1, &accuracies),
+            &[1.0, 1.0, 1.0, 1.0, 0.5, 0.5, 0.5, 0.25, 0.25],
+        );
+        assert_eq!(scheduler.learning_rate(1.0, 1), 0.2);
+    }
+}
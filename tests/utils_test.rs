@@ -1,9 +1,9 @@
 #[cfg(test)]
 mod tests {
-    use conv_nn::utils::{outer, SavingStrategy, TrainImage, TrainingData};
+    use conv_nn::utils::{deserialize_pair, outer, SavingStrategy, TrainImage, TrainingData};
     use ndarray::{array, Array3};
-    
-    
+    use serde::{Deserialize, Serialize};
+
     use std::path::PathBuf;
 
     #[test]
@@ -88,4 +88,25 @@ mod tests {
             _ => panic!("Expected Never"),
         }
     }
+
+    #[derive(Serialize, Deserialize)]
+    struct Window {
+        #[serde(deserialize_with = "deserialize_pair")]
+        stride: (usize, usize),
+    }
+
+    #[test]
+    fn test_deserialize_pair() {
+        let square: Window = serde_json::from_str(r#"{"stride": 3}"#).unwrap();
+        assert_eq!(square.stride, (3, 3));
+        let pair: Window = serde_json::from_str(r#"{"stride": [2, 1]}"#).unwrap();
+        assert_eq!(pair.stride, (2, 1));
+        assert!(serde_json::from_str::<Window>(r#"{"stride": "3"}"#).is_err());
+
+        let binary = bincode::serialize(&Window { stride: (2, 1) }).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Window>(&binary).unwrap().stride,
+            (2, 1)
+        );
+    }
 }